@@ -0,0 +1,132 @@
+//! Offline packer which combines the loose images of an `images.toml` into one texture atlas.
+//!
+//! Every packed image becomes a sprite sheet with a single rect of the atlas, such that the names
+//! used by the other metadata files stay the same while drawing them needs no texture switches.
+
+use std::{cmp::Reverse, path::Path};
+
+use macroquad::{math::Rect, texture::Image};
+use toml::{Table, Value};
+
+/// Pixels left free around every image, such that neighbours do not bleed into each other
+const PADDING: usize = 1;
+
+/// Packs the images into rows of an atlas, returns the atlas and the region of every image.
+/// Fails if the atlas would be larger than an `Image` can be.
+pub fn pack_atlas(images: &[Image]) -> Result<(Image, Vec<Rect>), String> {
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    order.sort_by_key(|x| Reverse(images[*x].height()));
+    let area: usize = images.iter().map(|x| (x.width() + PADDING) * (x.height() + PADDING)).sum();
+    let widest = images.iter().map(|x| x.width()).max().unwrap_or(0);
+    let width = ((area as f32).sqrt().ceil() as usize).next_power_of_two().max(widest + 2 * PADDING);
+
+    let mut rects = vec![Rect::default(); images.len()];
+    let (mut x, mut y, mut row_height) = (PADDING, PADDING, 0);
+    for i in order {
+        let image = &images[i];
+        if x + image.width() + PADDING > width {
+            x = PADDING;
+            y += row_height + PADDING;
+            row_height = 0;
+        }
+        rects[i] = Rect::new(x as f32, y as f32, image.width() as f32, image.height() as f32);
+        x += image.width() + PADDING;
+        row_height = row_height.max(image.height());
+    }
+    let height = y + row_height + PADDING;
+    let (Ok(atlas_width), Ok(atlas_height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(format!("atlas of {}x{} pixels is larger than {}x{}", width, height, u16::MAX, u16::MAX));
+    };
+
+    let mut atlas = Image {
+        bytes: vec![0; width * height * 4],
+        width: atlas_width,
+        height: atlas_height,
+    };
+    for (image, rect) in images.iter().zip(rects.iter()) {
+        let row = image.width() * 4;
+        for line in 0..image.height() {
+            let start = ((rect.y as usize + line) * width + rect.x as usize) * 4;
+            atlas.bytes[start..start + row].copy_from_slice(&image.bytes[line * row..(line + 1) * row]);
+        }
+    }
+    Ok((atlas, rects))
+}
+
+/// Packs the loose images of an `images.toml` into a PNG atlas, returns the new contents of the
+/// `images.toml`. Sprite sheets are kept as they are.
+pub fn pack_images_file(images_path: &str, atlas_path: &str) -> Result<String, String> {
+    let text = std::fs::read_to_string(images_path).map_err(|e| format!("could not read {}: {}", images_path, e))?;
+    let table: Table = toml::from_str(&text).map_err(|e| format!("could not parse {}: {}", images_path, e))?;
+    let dir = Path::new(images_path).parent().unwrap_or(Path::new(""));
+
+    let mut names = Vec::new();
+    let mut images = Vec::new();
+    for (name, value) in table.iter() {
+        let Some(path) = value.as_str() else { continue; };
+        let path = dir.join(path);
+        let bytes = std::fs::read(&path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let image = Image::from_file_with_format(&bytes, None).map_err(|e| format!("could not decode {}: {}", path.display(), e))?;
+        names.push(name.clone());
+        images.push(image);
+    }
+
+    let (atlas, rects) = pack_atlas(&images)?;
+    image::save_buffer(atlas_path, &atlas.bytes, atlas.width as u32, atlas.height as u32, image::ColorType::Rgba8)
+        .map_err(|e| format!("could not write {}: {}", atlas_path, e))?;
+
+    let atlas_path = Path::new(atlas_path);
+    let relative = atlas_path.strip_prefix(dir).unwrap_or(atlas_path).to_string_lossy().replace('\\', "/");
+    let mut packed = table.clone();
+    for (name, rect) in names.into_iter().zip(rects) {
+        let mut sheet = Table::new();
+        sheet.insert("path".to_owned(), Value::String(relative.clone()));
+        let rect = [rect.x, rect.y, rect.w, rect.h].map(|x| Value::Integer(x as i64));
+        sheet.insert("rects".to_owned(), Value::Array(vec![Value::Array(rect.to_vec())]));
+        packed.insert(name, Value::Table(sheet));
+    }
+    toml::to_string(&packed).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image whose pixels differ from those of the images with other seeds
+    fn image(width: u16, height: u16, seed: u8) -> Image {
+        let bytes = (0..width as usize * height as usize).flat_map(|i| [seed, i as u8, (i >> 8) as u8, 255]).collect();
+        Image { bytes, width, height }
+    }
+
+    #[test]
+    fn images_are_packed_apart_with_their_pixels() {
+        let sizes = [(16, 16), (5, 30), (40, 3), (1, 1), (16, 16), (9, 7), (64, 12)];
+        let images: Vec<Image> = sizes.iter().enumerate().map(|(i, (w, h))| image(*w, *h, i as u8)).collect();
+        let (atlas, rects) = pack_atlas(&images).unwrap();
+
+        let padding = PADDING as f32;
+        for (i, a) in rects.iter().enumerate() {
+            assert!(a.x >= padding && a.y >= padding, "{:?}", a);
+            assert!(a.right() + padding <= atlas.width as f32 && a.bottom() + padding <= atlas.height as f32, "{:?}", a);
+            for b in rects[i + 1..].iter() {
+                let padded = Rect::new(a.x - padding, a.y - padding, a.w + 2.0 * padding, a.h + 2.0 * padding);
+                assert!(padded.intersect(*b).is_none_or(|x| x.w == 0.0 || x.h == 0.0), "{:?} and {:?} overlap", a, b);
+            }
+        }
+        for (image, rect) in images.iter().zip(rects.iter()) {
+            assert_eq!((rect.w, rect.h), (image.width as f32, image.height as f32));
+            for y in 0..image.height() {
+                for x in 0..image.width() {
+                    let pixel = |image: &Image, x: usize, y: usize| image.bytes[(y * image.width() + x) * 4..][..4].to_vec();
+                    assert_eq!(pixel(&atlas, rect.x as usize + x, rect.y as usize + y), pixel(image, x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn atlases_larger_than_an_image_fail() {
+        let images = [image(1, u16::MAX, 0)];
+        assert!(pack_atlas(&images).is_err());
+    }
+}
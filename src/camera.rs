@@ -0,0 +1,115 @@
+//! Camera which smoothly follows the players, looks ahead toward where they aim and shakes.
+//!
+//! Screen shake is driven by trauma: events add trauma with `CameraRig::add_trauma`, which decays
+//! over time, and the camera is displaced by the square of the trauma such that small amounts
+//! are subtle while large amounts are violent. The rig is presentation only and not part of the
+//! `State`. The simulation records a `ShakeEvent` instead of shaking the camera, the events of
+//! ticks which are re-simulated after a rollback are dropped such that every shot shakes once.
+
+use macroquad::prelude::*;
+
+use crate::{state::Rect, ActorHandle};
+
+/// Half of the width of the view in world units when framing a single player
+const MIN_SIZE: f32 = 12.0;
+/// World units kept free around the players when framing several players
+const MARGIN: f32 = 4.0;
+/// World units the camera looks ahead in the direction the players aim
+const LOOK_AHEAD: f32 = 2.0;
+/// Rate at which the camera catches up with the players, higher is stiffer
+const SMOOTHING: f32 = 6.0;
+/// Trauma removed per second
+const TRAUMA_DECAY: f32 = 1.5;
+/// Displacement in world units at full trauma
+const MAX_SHAKE: f32 = 0.6;
+/// Speed of the shake noise
+const SHAKE_FREQUENCY: f32 = 25.0;
+
+/// Emitted by the simulation when an actor does something which shakes the camera of its player
+#[derive(Clone, Copy)]
+pub struct ShakeEvent {
+    pub actor: ActorHandle,
+    /// trauma added, from 0 to 1
+    pub trauma: f32,
+}
+
+#[derive(Default)]
+pub struct CameraRig {
+    /// center of the view without shake
+    pub center: Vec2,
+    /// half of the width of the view in world units
+    pub size: f32,
+    /// strength of the shake, from 0 to 1
+    pub trauma: f32,
+    /// seconds the rig has been updated, drives the shake noise
+    time: f32,
+    /// the rig has framed the players before, such that it smoothly moves from there
+    started: bool,
+}
+
+impl CameraRig {
+    /// Shakes the camera, `amount` is from 0 to 1 where 1 is the strongest shake
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).clamp(0.0, 1.0);
+    }
+
+    /// Moves the view toward framing the `positions` of the players, looking ahead in the mean of
+    /// their `aims` and keeping within `bounds`. The first update snaps to the players.
+    pub fn update(&mut self, positions: &[Vec2], aims: &[Vec2], bounds: &Rect, aspect: f32, dt: f32) {
+        let mut center = self.center;
+        let mut size = MIN_SIZE;
+        if !positions.is_empty() {
+            let centroid = positions.iter().sum::<Vec2>() / positions.len() as f32;
+            let spread = positions.iter().fold(Vec2::ZERO, |a, b| a.max((*b - centroid).abs()));
+            if positions.len() > 1 {
+                size = f32::max(size, spread.x + MARGIN);
+                size = f32::max(size, (spread.y + MARGIN) * aspect);
+            }
+            let aim = aims.iter().sum::<Vec2>() / aims.len().max(1) as f32;
+            center = centroid + aim * LOOK_AHEAD;
+        }
+
+        let t = match self.started {
+            true => 1.0 - (-SMOOTHING * dt).exp(),
+            false => 1.0,
+        };
+        self.started = true;
+        self.size += (size - self.size) * t;
+        self.center = clamp_to_bounds(self.center.lerp(center, t), Vec2::new(self.size, self.size / aspect), bounds);
+
+        self.time += dt;
+        self.trauma = (self.trauma - TRAUMA_DECAY * dt).max(0.0);
+    }
+
+    /// Displacement of the view by the shake
+    pub fn shake(&self) -> Vec2 {
+        let t = self.time * SHAKE_FREQUENCY;
+        let noise = Vec2::new(noise(t, 0.0), noise(t, 17.0));
+        noise * MAX_SHAKE * self.trauma * self.trauma
+    }
+
+    /// Sets the target and zoom of the camera to the view
+    pub fn apply(&self, camera: &mut Camera2D, aspect: f32) {
+        let zoom = 1.0 / self.size;
+        camera.target = self.center + self.shake();
+        camera.zoom = Vec2::new(zoom, zoom * aspect);
+    }
+}
+
+/// Moves a view of the given half extents such that it stays within the bounds, views which
+/// are larger than the bounds are centered on them
+fn clamp_to_bounds(center: Vec2, half: Vec2, bounds: &Rect) -> Vec2 {
+    let axis = |x: f32, half: f32, min: f32, max: f32| match max - min > half * 2.0 {
+        true => x.clamp(min + half, max - half),
+        false => (min + max) / 2.0,
+    };
+    Vec2::new(
+        axis(center.x, half.x, bounds.left, bounds.right()),
+        axis(center.y, half.y, bounds.top, bounds.bottom()),
+    )
+}
+
+/// Smooth noise from -1 to 1, different seeds give unrelated curves
+fn noise(t: f32, seed: f32) -> f32 {
+    ((t + seed).sin() + (t * 2.3 + seed * 1.7).sin() * 0.5 + (t * 4.1 + seed * 2.9).sin() * 0.25) / 1.75
+}
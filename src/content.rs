@@ -0,0 +1,90 @@
+//! Content packs, the base game and the mods layered on top of it.
+//!
+//! Every pack is a directory which can contain `images.toml`, `effects.toml`, `weapons.toml` and
+//! `actors.toml`. Packs are applied in order, tables of a later pack are merged key by key into the
+//! tables of the same name of earlier packs, such that a mod can add entries or override single
+//! properties. Mods live in `mods/<id>` and are described by a `mod.toml` manifest.
+
+use toml::{Table, Value};
+
+/// Directory of the base game
+pub const BASE_ROOT: &str = "assets";
+
+/// Directory containing a directory for every mod
+pub const MODS_DIR: &str = "mods";
+
+/// Name of the manifest file of a mod
+pub const MANIFEST: &str = "mod.toml";
+
+#[derive(Clone)]
+pub struct ContentPack {
+    /// name of the directory of the mod, empty for the base game
+    pub id: String,
+    /// directory containing the files of the pack
+    pub root: String,
+    pub name: String,
+    pub version: String,
+    /// ids of the mods which must be enabled before this one
+    pub dependencies: Vec<String>,
+}
+
+/// Problem found in a manifest, with the key it was found at
+pub struct ManifestError {
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl ContentPack {
+    pub fn base() -> Self {
+        Self {
+            id: String::new(),
+            root: BASE_ROOT.to_owned(),
+            name: "Guy vs Zombies".to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    /// Path of the manifest of the mod
+    pub fn manifest_path(id: &str) -> String {
+        format!("{}/{}/{}", MODS_DIR, id, MANIFEST)
+    }
+
+    /// Reads the manifest of the mod with the id
+    pub fn from_manifest(id: &str, manifest: &Table) -> Result<Self, ManifestError> {
+        let error = |key: &str, message: &str| ManifestError {
+            key: Some(key.to_owned()),
+            message: message.to_owned(),
+        };
+        let get_str = |key: &str| match manifest.get(key) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(_) => Err(error(key, "expected a string")),
+            None => Err(error(key, "missing")),
+        };
+        let dependencies = match manifest.get("dependencies") {
+            None => Vec::new(),
+            Some(Value::Array(deps)) => deps
+                .iter()
+                .map(|x| x.as_str().map(|x| x.to_owned()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| error("dependencies", "expected an array of strings"))?,
+            Some(_) => return Err(error("dependencies", "expected an array of strings")),
+        };
+        Ok(Self {
+            id: id.to_owned(),
+            root: format!("{}/{}", MODS_DIR, id),
+            name: get_str("name")?,
+            version: get_str("version")?,
+            dependencies,
+        })
+    }
+
+    pub fn is_base(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    /// Path of a file of the pack
+    pub fn path(&self, file: &str) -> String {
+        format!("{}/{}", self.root, file)
+    }
+}
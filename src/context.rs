@@ -1,11 +1,36 @@
 use macroquad::camera::Camera2D;
 
-use crate::{State, Metadata};
+use crate::{CameraRig, ShakeEvent, DrawList, Gamepads, InputSource, State, Metadata, Net, PlayerInput, Replay, SaveMenu, TraceWriter, MetadataWatcher};
 
 #[derive(Default)]
 pub struct Context {
     pub camera:Camera2D,
+    /// moves `camera` to follow the players and shakes it
+    pub camera_rig:CameraRig,
+    /// shakes emitted by the ticks simulated since the camera was last updated
+    pub shake_events:Vec<ShakeEvent>,
     pub metadata:Metadata,
     pub state:State,
+    /// input sources of the local players, indexed by player
+    pub inputs:Vec<InputSource>,
+    /// gamepads read by `InputSource::Gamepad`
+    pub gamepads:Gamepads,
+    /// input of each player during the current tick, read from `inputs`
+    pub player_inputs:Vec<PlayerInput>,
+    /// seconds simulated by the current tick
+    pub dt:f32,
+    /// set when hosting or connected to a host
+    pub net:Option<Net>,
+    /// set when recording a trace of every tick
+    pub trace:Option<TraceWriter>,
+    /// set when playing back the inputs of a trace
+    pub replay:Option<Replay>,
+    /// seconds played, including the playtime of the loaded save
+    pub playtime:f32,
+    pub save_menu:SaveMenu,
+    /// reloads the metadata when its files change
+    pub watcher:MetadataWatcher,
+    /// draw commands of the current frame, executed by a `Renderer` after the systems have run
+    pub draw_list:DrawList,
     pub debug:bool
 }
\ No newline at end of file
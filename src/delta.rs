@@ -0,0 +1,488 @@
+//! Delta compression of `StateSnapshot` against an acknowledged baseline.
+//!
+//! Only actors and fields which differ from the baseline are encoded. Positions, velocities and
+//! directions are quantised and actor and weapon names are replaced by the compact ids of `Metadata`.
+//! Fields which advance with time, the age, animation time and status effect timers, are advanced
+//! by the receiver and only sent when that does not give the same result.
+//! Encoding is lossy, so the sender must keep the snapshot produced by `apply` as the next baseline,
+//! since that is what the receiver ends up with.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use glam::{Vec2, Vec4};
+use serde::{Deserialize, Serialize};
+
+use crate::{state::Rect, ActorHandle, ActorSnapshot, ActorState, Animation, Clock, GameState, Metadata, Rng, StateSnapshot, StatusEffect};
+
+/// Quantisation steps per world unit of positions and velocities
+const POS_SCALE: f32 = 100.0;
+
+/// Seconds by which a field advanced by the receiver may differ before it is sent
+const TIME_TOLERANCE: f32 = 0.01;
+
+/// Quantisation steps per unit of directions
+const DIR_SCALE: f32 = 127.0;
+
+/// Quantisation steps per radian of facing
+const ANGLE_SCALE: f32 = 1000.0;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedVec2(i32, i32);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ActorField {
+    Info(u16),
+    Weapon(u16),
+    Pos(QuantizedVec2),
+    Vel(QuantizedVec2),
+    LocomotionDir(i8, i8),
+    AttackDir(i8, i8),
+    Facing(i16),
+    Health(f32),
+    Color([u8; 4]),
+    Animation(Animation),
+    Age(f32),
+    WeaponCooldown(f32),
+    PainTimer(f32),
+    DeadTime(f32),
+    Effects(Vec<StatusEffect>),
+    /// every field of the state, sent when one of the fields not listed above has changed
+    State(Box<ActorState>),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ActorDelta {
+    pub handle: ActorHandle,
+    pub fields: Vec<ActorField>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SnapshotDelta {
+    /// tick of the baseline snapshot, none if the delta is against an empty snapshot
+    pub baseline: Option<u32>,
+    /// seconds simulated since the baseline
+    pub elapsed: f32,
+    pub spawner: Option<Clock>,
+    pub game_state: Option<GameState>,
+    pub round: Option<u32>,
+    pub bounds: Option<Rect>,
+    pub rng: Option<Rng>,
+    /// handles of the actors controlled by the players
+    pub players: Option<Vec<ActorHandle>>,
+    pub scores: Option<Vec<u32>>,
+    pub removed: Vec<ActorHandle>,
+    pub actors: Vec<ActorDelta>,
+}
+
+/// Content of a snapshot which cannot be encoded
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeltaError {
+    /// the actor has no compact id in the metadata
+    UnknownActor(String),
+    /// the weapon has no compact id in the metadata
+    UnknownWeapon(String),
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::UnknownActor(name) => write!(f, "unknown actor \"{}\"", name),
+            DeltaError::UnknownWeapon(name) => write!(f, "unknown weapon \"{}\"", name),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+fn quantize(v: Vec2, scale: f32) -> QuantizedVec2 {
+    let q = |x: f32| (x * scale).round() as i32;
+    QuantizedVec2(q(v.x), q(v.y))
+}
+
+fn dequantize(v: QuantizedVec2, scale: f32) -> Vec2 {
+    Vec2::new(v.0 as f32 / scale, v.1 as f32 / scale)
+}
+
+fn quantize_dir(v: Vec2) -> (i8, i8) {
+    let q = |x: f32| (x * DIR_SCALE).round().clamp(-DIR_SCALE, DIR_SCALE) as i8;
+    (q(v.x), q(v.y))
+}
+
+fn dequantize_dir(x: i8, y: i8) -> Vec2 {
+    Vec2::new(x as f32 / DIR_SCALE, y as f32 / DIR_SCALE)
+}
+
+fn quantize_color(c: Vec4) -> [u8; 4] {
+    let q = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [q(c.x), q(c.y), q(c.z), q(c.w)]
+}
+
+fn dequantize_color(c: [u8; 4]) -> Vec4 {
+    Vec4::new(c[0] as f32, c[1] as f32, c[2] as f32, c[3] as f32) / 255.0
+}
+
+fn quantize_angle(a: f32) -> i16 {
+    (a * ANGLE_SCALE).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Advances the fields of the state which change with time as the simulation does
+fn advance_actor(state: &mut ActorState, elapsed: f32) {
+    state.age += elapsed;
+    state.animation.time += elapsed;
+    for effect in state.effects.iter_mut() {
+        effect.timer.tick(elapsed);
+    }
+    state.effects.retain(|x| !x.timer.is_done());
+}
+
+fn same_time(a: f32, b: f32) -> bool {
+    (a - b).abs() <= TIME_TOLERANCE
+}
+
+fn same_animation(a: &Animation, b: &Animation) -> bool {
+    a.clip == b.clip && a.started == b.started && same_time(a.time, b.time)
+}
+
+fn same_effects(a: &[StatusEffect], b: &[StatusEffect]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b.iter()).all(|(a, b)| {
+            a.info.name == b.info.name && a.stacks == b.stacks && a.timer.end_time == b.timer.end_time && same_time(a.timer.timer, b.timer.timer)
+        })
+}
+
+/// Fields of `state` which differ from `base` advanced by `elapsed` seconds
+fn diff_actor(base: &ActorState, state: &ActorState, elapsed: f32) -> Vec<ActorField> {
+    let mut base = base.clone();
+    advance_actor(&mut base, elapsed);
+    let base = &base;
+    let mut fields = Vec::new();
+    let pos = quantize(state.pos, POS_SCALE);
+    if pos != quantize(base.pos, POS_SCALE) {
+        fields.push(ActorField::Pos(pos));
+    }
+    let vel = quantize(state.vel, POS_SCALE);
+    if vel != quantize(base.vel, POS_SCALE) {
+        fields.push(ActorField::Vel(vel));
+    }
+    let (x, y) = quantize_dir(state.locomotion_dir);
+    if (x, y) != quantize_dir(base.locomotion_dir) {
+        fields.push(ActorField::LocomotionDir(x, y));
+    }
+    let (x, y) = quantize_dir(state.attack_dir);
+    if (x, y) != quantize_dir(base.attack_dir) {
+        fields.push(ActorField::AttackDir(x, y));
+    }
+    let facing = quantize_angle(state.facing);
+    if facing != quantize_angle(base.facing) {
+        fields.push(ActorField::Facing(facing));
+    }
+    if state.health != base.health {
+        fields.push(ActorField::Health(state.health));
+    }
+    let color = quantize_color(state.color);
+    if color != quantize_color(base.color) {
+        fields.push(ActorField::Color(color));
+    }
+    if !same_animation(&state.animation, &base.animation) {
+        fields.push(ActorField::Animation(state.animation));
+    }
+    if !same_time(state.age, base.age) {
+        fields.push(ActorField::Age(state.age));
+    }
+    if !same_effects(&state.effects, &base.effects) {
+        fields.push(ActorField::Effects(state.effects.clone()));
+    }
+    if state.weapon_cooldown != base.weapon_cooldown {
+        fields.push(ActorField::WeaponCooldown(state.weapon_cooldown));
+    }
+    if state.pain_timer.timer != base.pain_timer.timer {
+        fields.push(ActorField::PainTimer(state.pain_timer.timer));
+    }
+    if state.dead_time != base.dead_time {
+        fields.push(ActorField::DeadTime(state.dead_time));
+    }
+
+    // the remaining fields rarely change, so they are sent together when they do
+    let mut rest = base.clone();
+    apply_fields(&mut rest, &fields);
+    let mut expected = state.clone();
+    expected.pos = rest.pos;
+    expected.vel = rest.vel;
+    expected.locomotion_dir = rest.locomotion_dir;
+    expected.attack_dir = rest.attack_dir;
+    expected.facing = rest.facing;
+    expected.color = rest.color;
+    expected.age = rest.age;
+    expected.animation = rest.animation;
+    expected.effects = rest.effects.clone();
+    if expected != rest {
+        fields.push(ActorField::State(Box::new(state.clone())));
+    }
+    fields
+}
+
+fn apply_fields(state: &mut ActorState, fields: &[ActorField]) {
+    for field in fields.iter() {
+        match field {
+            ActorField::Info(_) | ActorField::Weapon(_) => {}
+            ActorField::Pos(v) => state.pos = dequantize(*v, POS_SCALE),
+            ActorField::Vel(v) => state.vel = dequantize(*v, POS_SCALE),
+            ActorField::LocomotionDir(x, y) => state.locomotion_dir = dequantize_dir(*x, *y),
+            ActorField::AttackDir(x, y) => state.attack_dir = dequantize_dir(*x, *y),
+            ActorField::Facing(a) => state.facing = *a as f32 / ANGLE_SCALE,
+            ActorField::Health(v) => state.health = *v,
+            ActorField::Color(c) => state.color = dequantize_color(*c),
+            ActorField::Animation(v) => state.animation = *v,
+            ActorField::Age(v) => state.age = *v,
+            ActorField::WeaponCooldown(v) => state.weapon_cooldown = *v,
+            ActorField::PainTimer(v) => state.pain_timer.timer = *v,
+            ActorField::DeadTime(v) => state.dead_time = *v,
+            ActorField::Effects(v) => state.effects = v.clone(),
+            ActorField::State(s) => *state = s.as_ref().clone(),
+        }
+    }
+}
+
+fn changed<T: PartialEq + Clone>(base: Option<&T>, value: &T) -> Option<T> {
+    match base {
+        Some(base) if base == value => None,
+        _ => Some(value.clone()),
+    }
+}
+
+fn player_handles(snapshot: &StateSnapshot) -> Vec<ActorHandle> {
+    snapshot
+        .players
+        .iter()
+        .filter_map(|x| snapshot.actors.get(*x))
+        .map(|x| x.handle)
+        .collect()
+}
+
+impl SnapshotDelta {
+    /// Encodes the difference between the baseline, identified by its tick, and the snapshot which
+    /// was taken `elapsed` seconds of simulation later.
+    ///
+    /// Fails if an actor or weapon of the snapshot is not defined by the metadata.
+    pub fn encode(baseline: Option<(u32, &StateSnapshot)>, snapshot: &StateSnapshot, elapsed: f32, md: &Metadata) -> Result<Self, DeltaError> {
+        let actor_id = |name: &str| md.actor_id(name).ok_or_else(|| DeltaError::UnknownActor(name.to_owned()));
+        let weapon_id = |name: &str| md.weapon_id(name).ok_or_else(|| DeltaError::UnknownWeapon(name.to_owned()));
+        let base = baseline.map(|x| x.1);
+        let base_actors: HashMap<ActorHandle, &ActorSnapshot> = base
+            .map(|x| x.actors.iter().map(|x| (x.handle, x)).collect())
+            .unwrap_or_default();
+        let handles: HashSet<ActorHandle> = snapshot.actors.iter().map(|x| x.handle).collect();
+        let removed = base_actors.keys().filter(|x| !handles.contains(x)).copied().collect();
+        let mut actors = Vec::new();
+        for actor in snapshot.actors.iter() {
+            let fields = match base_actors.get(&actor.handle) {
+                Some(base_actor) => {
+                    let mut fields = Vec::new();
+                    if base_actor.info != actor.info {
+                        fields.push(ActorField::Info(actor_id(&actor.info)?));
+                    }
+                    if base_actor.weapon != actor.weapon {
+                        fields.push(ActorField::Weapon(weapon_id(&actor.weapon)?));
+                    }
+                    fields.extend(diff_actor(&base_actor.state, &actor.state, elapsed));
+                    fields
+                }
+                None => vec![
+                    ActorField::Info(actor_id(&actor.info)?),
+                    ActorField::Weapon(weapon_id(&actor.weapon)?),
+                    ActorField::State(Box::new(actor.state.clone())),
+                ],
+            };
+            if !fields.is_empty() {
+                actors.push(ActorDelta { handle: actor.handle, fields });
+            }
+        }
+
+        Ok(SnapshotDelta {
+            baseline: baseline.map(|x| x.0),
+            elapsed,
+            spawner: changed(base.map(|x| &x.spawner), &snapshot.spawner),
+            game_state: changed(base.map(|x| &x.game_state), &snapshot.game_state),
+            round: changed(base.map(|x| &x.round), &snapshot.round),
+            bounds: changed(base.map(|x| &x.bounds), &snapshot.bounds),
+            rng: changed(base.map(|x| &x.rng), &snapshot.rng),
+            players: changed(base.map(player_handles).as_ref(), &player_handles(snapshot)),
+            scores: changed(base.map(|x| &x.scores), &snapshot.scores),
+            removed,
+            actors,
+        })
+    }
+
+    /// Applies the delta to the baseline it was encoded against, returns none if the delta is malformed
+    pub fn apply(&self, baseline: Option<&StateSnapshot>, md: &Metadata) -> Option<StateSnapshot> {
+        let removed: HashSet<ActorHandle> = self.removed.iter().copied().collect();
+        let mut actors: Vec<ActorSnapshot> = baseline.map(|x| x.actors.clone()).unwrap_or_default();
+        actors.retain(|x| !removed.contains(&x.handle));
+        for actor in actors.iter_mut() {
+            advance_actor(&mut actor.state, self.elapsed);
+        }
+        let mut indices: HashMap<ActorHandle, usize> =
+            actors.iter().enumerate().map(|(i, x)| (x.handle, i)).collect();
+        for delta in self.actors.iter() {
+            let index = match indices.get(&delta.handle) {
+                Some(index) => *index,
+                None => {
+                    let Some(ActorField::State(state)) = delta.fields.iter().find(|x| matches!(x, ActorField::State(_))) else {
+                        return None;
+                    };
+                    actors.push(ActorSnapshot {
+                        handle: delta.handle,
+                        info: String::new(),
+                        weapon: String::new(),
+                        state: state.as_ref().clone(),
+                    });
+                    indices.insert(delta.handle, actors.len() - 1);
+                    actors.len() - 1
+                }
+            };
+            let actor = &mut actors[index];
+            for field in delta.fields.iter() {
+                match field {
+                    ActorField::Info(id) => actor.info = md.actor_ids.get(*id as usize)?.clone(),
+                    ActorField::Weapon(id) => actor.weapon = md.weapon_ids.get(*id as usize)?.clone(),
+                    _ => {}
+                }
+            }
+            apply_fields(&mut actor.state, &delta.fields);
+        }
+
+        let players = match (&self.players, baseline) {
+            (Some(players), _) => players.clone(),
+            (None, Some(baseline)) => player_handles(baseline),
+            (None, None) => return None,
+        };
+        let players = players.iter().map(|x| indices.get(x).copied()).collect::<Option<Vec<_>>>()?;
+
+        Some(StateSnapshot {
+            spawner: self.spawner.clone().or(baseline.map(|x| x.spawner.clone()))?,
+            players,
+            scores: self.scores.clone().or(baseline.map(|x| x.scores.clone()))?,
+            game_state: self.game_state.clone().or(baseline.map(|x| x.game_state.clone()))?,
+            round: self.round.or(baseline.map(|x| x.round))?,
+            actors,
+            bounds: self.bounds.or(baseline.map(|x| x.bounds))?,
+            rng: self.rng.clone().or(baseline.map(|x| x.rng.clone()))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::State;
+
+    fn metadata() -> Metadata {
+        Metadata::from_disk(&[], false).unwrap()
+    }
+
+    /// Snapshot of a player and a burning zombie at positions which are quantised without loss
+    fn snapshot(md: &Metadata) -> StateSnapshot {
+        let mut state = State::default();
+        let guy = state.spawn_actor(md.actors.get("guy").unwrap().clone());
+        guy.pos = Vec2::new(1.25, -2.5);
+        let guy = guy.handle;
+        state.players.push(guy);
+        state.scores.push(3);
+        let zombie = state.spawn_actor(md.actors.get("zombie").unwrap().clone());
+        zombie.pos = Vec2::new(-4.0, 0.75);
+        zombie.apply_effect(md.effects.get("burning").unwrap());
+        StateSnapshot::create_snapshot(&state, md)
+    }
+
+    /// Advances the snapshot by ticks of `dt` seconds as the simulation would, returns the seconds simulated
+    fn advance(snapshot: &mut StateSnapshot, ticks: u32, dt: f32) -> f32 {
+        for _ in 0..ticks {
+            for actor in snapshot.actors.iter_mut() {
+                actor.state.age += dt;
+                actor.state.animation.time += dt;
+                for effect in actor.state.effects.iter_mut() {
+                    effect.timer.tick(dt);
+                }
+            }
+        }
+        ticks as f32 * dt
+    }
+
+    #[test]
+    fn apply_reproduces_the_snapshot() {
+        let md = metadata();
+        let s0 = snapshot(&md);
+        let delta = SnapshotDelta::encode(None, &s0, 0.0, &md).unwrap();
+        let applied = delta.apply(None, &md).unwrap();
+        assert_eq!(applied.checksum(), s0.checksum());
+
+        let mut s1 = s0.clone();
+        s1.actors[1].state.pos = Vec2::new(-3.5, 1.0);
+        s1.actors[0].state.health -= 10.0;
+        s1.scores[0] += 1;
+        let delta = SnapshotDelta::encode(Some((7, &s0)), &s1, 0.0, &md).unwrap();
+        assert_eq!(delta.baseline, Some(7));
+        let applied = delta.apply(Some(&s0), &md).unwrap();
+        assert_eq!(applied.checksum(), s1.checksum());
+    }
+
+    #[test]
+    fn fields_advanced_with_time_are_not_sent() {
+        let md = metadata();
+        let s0 = snapshot(&md);
+        let mut s1 = s0.clone();
+        let elapsed = advance(&mut s1, 10, 1.0 / 60.0);
+        let delta = SnapshotDelta::encode(Some((0, &s0)), &s1, elapsed, &md).unwrap();
+        assert!(delta.actors.is_empty());
+
+        let applied = delta.apply(Some(&s0), &md).unwrap();
+        for (applied, actor) in applied.actors.iter().zip(s1.actors.iter()) {
+            assert!((applied.state.age - actor.state.age).abs() < TIME_TOLERANCE);
+            assert!(same_animation(&applied.state.animation, &actor.state.animation));
+            assert!(same_effects(&applied.state.effects, &actor.state.effects));
+        }
+    }
+
+    #[test]
+    fn new_effects_are_sent_without_the_whole_state() {
+        let md = metadata();
+        let s0 = snapshot(&md);
+        let mut s1 = s0.clone();
+        let elapsed = advance(&mut s1, 1, 1.0 / 60.0);
+        let slowed = md.effects.get("slowed").unwrap();
+        let guy = &mut s1.actors[0].state;
+        guy.effects.push(StatusEffect { info: slowed.clone(), timer: crate::Timer::start(slowed.duration), stacks: 1 });
+
+        let delta = SnapshotDelta::encode(Some((0, &s0)), &s1, elapsed, &md).unwrap();
+        assert_eq!(delta.actors.len(), 1);
+        assert!(matches!(delta.actors[0].fields.as_slice(), [ActorField::Effects(x)] if x.len() == 1));
+        let applied = delta.apply(Some(&s0), &md).unwrap();
+        assert_eq!(applied.actors[0].state.effects, s1.actors[0].state.effects);
+    }
+
+    #[test]
+    fn distant_positions_are_not_clamped() {
+        let md = metadata();
+        let s0 = snapshot(&md);
+        let mut s1 = s0.clone();
+        s1.actors[1].state.pos = Vec2::new(1000.0, -2500.5);
+        let delta = SnapshotDelta::encode(Some((0, &s0)), &s1, 0.0, &md).unwrap();
+        let applied = delta.apply(Some(&s0), &md).unwrap();
+        assert_eq!(applied.actors[1].state.pos, Vec2::new(1000.0, -2500.5));
+    }
+
+    #[test]
+    fn unknown_content_is_an_error() {
+        let md = metadata();
+        let mut s0 = snapshot(&md);
+        s0.actors[1].weapon = "laser".to_owned();
+        let e = SnapshotDelta::encode(None, &s0, 0.0, &md).err();
+        assert_eq!(e, Some(DeltaError::UnknownWeapon("laser".to_owned())));
+        s0.actors[1].info = "ghost".to_owned();
+        let e = SnapshotDelta::encode(None, &s0, 0.0, &md).err();
+        assert_eq!(e, Some(DeltaError::UnknownActor("ghost".to_owned())));
+    }
+}
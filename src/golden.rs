@@ -0,0 +1,101 @@
+//! Golden image tests of the drawing systems.
+//!
+//! Every test loads a text save from `tests/golden`, draws one frame of it with the
+//! `SoftwareRenderer` and compares it with the PNG of the same name. Only the `hud` test draws the
+//! HUD, such that changes of the HUD do not affect the other golden images. Set `UPDATE_GOLDEN=1` to
+//! write the current frames as the new golden images after an intended change of the drawing.
+//! Frames which do not match are written to `target/golden` for inspection.
+
+use std::path::{Path, PathBuf};
+
+use macroquad::texture::Image;
+
+use crate::{read_save_file, systems, Context, Metadata, Renderer, SoftwareRenderer};
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 360;
+/// Largest difference of a color channel for pixels which are considered equal
+const CHANNEL_TOLERANCE: u8 = 8;
+/// Fraction of pixels which may differ, such that rounding differences between platforms pass
+const PIXEL_TOLERANCE: f32 = 0.002;
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+/// Draws one frame of the save in `tests/golden`
+fn render(name: &str, hud: bool) -> SoftwareRenderer {
+    let metadata = Metadata::from_disk(&[], false).unwrap();
+    let path = golden_dir().join(format!("{}.ron", name));
+    let save = read_save_file(path.to_str().unwrap()).unwrap();
+    let (state, warnings) = save.snapshot.load_snapshot(&metadata);
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    let mut context = Context { metadata, state, ..Default::default() };
+    let mut renderer = SoftwareRenderer::new(WIDTH, HEIGHT);
+    context.draw_list.begin(renderer.screen_size());
+    systems::draw_state(&mut context, hud);
+    renderer.render(&context.draw_list.commands);
+    renderer
+}
+
+/// Number of pixels which differ by more than `CHANNEL_TOLERANCE` in any channel
+fn different_pixels(a: &Image, b: &Image) -> usize {
+    a.bytes.chunks(4)
+        .zip(b.bytes.chunks(4))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > CHANNEL_TOLERANCE))
+        .count()
+}
+
+fn assert_golden(name: &str, hud: bool) {
+    let renderer = render(name, hud);
+    let golden_path = golden_dir().join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        renderer.save_png(golden_path.to_str().unwrap()).unwrap();
+        return;
+    }
+
+    let actual = renderer.to_image();
+    let bytes = std::fs::read(&golden_path).unwrap_or_else(|e| panic!("could not read {}: {}, run with UPDATE_GOLDEN=1 to create it", golden_path.display(), e));
+    let golden = Image::from_file_with_format(&bytes, None).unwrap();
+    let matches = match (golden.width, golden.height) == (actual.width, actual.height) {
+        true => different_pixels(&golden, &actual) as f32 <= PIXEL_TOLERANCE * (WIDTH * HEIGHT) as f32,
+        false => false,
+    };
+    if !matches {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("golden");
+        std::fs::create_dir_all(&dir).unwrap();
+        let actual_path = dir.join(format!("{}.png", name));
+        renderer.save_png(actual_path.to_str().unwrap()).unwrap();
+        panic!("{} differs from {}, see {}", name, golden_path.display(), actual_path.display());
+    }
+}
+
+/// Sprites flipped to face left, weapons mounted in four directions, walking and dead guys
+#[test]
+fn facing() {
+    assert_golden("facing", false);
+}
+
+/// Overlapping actors drawn from top to bottom, tints, corpses and bullets rotated to face
+#[test]
+fn crowd() {
+    assert_golden("crowd", false);
+}
+
+/// Firing frames of weapons, muzzle flashes and muzzle lights
+#[test]
+fn firing() {
+    assert_golden("firing", false);
+}
+
+/// Camera following a player in the corner of bounds larger than the screen, clamped to the bounds
+#[test]
+fn follow() {
+    assert_golden("follow", false);
+}
+
+/// Health bars, weapons, scores, ammo and dead players in the HUD and the progress of spawning
+#[test]
+fn hud() {
+    assert_golden("hud", true);
+}
@@ -0,0 +1,53 @@
+//! Reloading of metadata while the game is running.
+//!
+//! The files the metadata was loaded from are polled for changes, such that edits to the TOML files
+//! or images take effect without a restart. Metadata is not reloaded while networked, since peers
+//! and clients would no longer agree on the content and its compact ids.
+
+use std::{collections::HashMap, time::SystemTime};
+
+use crate::Metadata;
+
+/// Seconds between checking the files for changes
+const POLL_INTERVAL: f64 = 0.5;
+
+#[derive(Default)]
+pub struct MetadataWatcher {
+    /// modification time of every file, none if it could not be read
+    files: HashMap<String, Option<SystemTime>>,
+    next_poll: f64,
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+impl MetadataWatcher {
+    pub fn new(md: &Metadata) -> Self {
+        let mut watcher = Self::default();
+        watcher.watch(md);
+        watcher
+    }
+
+    /// Watches the files of the metadata instead of the files watched so far
+    pub fn watch(&mut self, md: &Metadata) {
+        self.files = md.files().into_iter().map(|x| (x.clone(), modified(&x))).collect();
+    }
+
+    /// Whether any of the files has changed since the last poll
+    pub fn changed(&mut self, now: f64) -> bool {
+        if now < self.next_poll {
+            return false;
+        }
+        self.next_poll = now + POLL_INTERVAL;
+        let mut changed = false;
+        for (path, time) in self.files.iter_mut() {
+            let new_time = modified(path);
+            if new_time != *time {
+                *time = new_time;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
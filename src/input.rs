@@ -0,0 +1,320 @@
+use macroquad::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Keys used by a player controlling an actor with the keyboard
+pub struct KeyboardLayout {
+    pub up: KeyCode,
+    pub down: KeyCode,
+    pub left: KeyCode,
+    pub right: KeyCode,
+    pub attack_up: Option<KeyCode>,
+    pub attack_down: Option<KeyCode>,
+    pub attack_left: Option<KeyCode>,
+    pub attack_right: Option<KeyCode>,
+    /// keys selecting fists, pistol, machinegun and rifle
+    pub weapons: [KeyCode; 4],
+    /// aim with the mouse and attack with the left mouse button
+    pub mouse: bool,
+}
+
+/// WASD to move, arrows or mouse to attack
+pub const SOLO: KeyboardLayout = KeyboardLayout {
+    up: KeyCode::W,
+    down: KeyCode::S,
+    left: KeyCode::A,
+    right: KeyCode::D,
+    attack_up: Some(KeyCode::Up),
+    attack_down: Some(KeyCode::Down),
+    attack_left: Some(KeyCode::Left),
+    attack_right: Some(KeyCode::Right),
+    weapons: [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4],
+    mouse: true,
+};
+
+/// WASD to move, mouse to attack
+pub const KEYBOARD_LEFT: KeyboardLayout = KeyboardLayout {
+    attack_up: None,
+    attack_down: None,
+    attack_left: None,
+    attack_right: None,
+    ..SOLO
+};
+
+/// IJKL to move, arrows to attack
+pub const KEYBOARD_RIGHT: KeyboardLayout = KeyboardLayout {
+    up: KeyCode::I,
+    down: KeyCode::K,
+    left: KeyCode::J,
+    right: KeyCode::L,
+    attack_up: Some(KeyCode::Up),
+    attack_down: Some(KeyCode::Down),
+    attack_left: Some(KeyCode::Left),
+    attack_right: Some(KeyCode::Right),
+    weapons: [KeyCode::Key7, KeyCode::Key8, KeyCode::Key9, KeyCode::Key0],
+    mouse: false,
+};
+
+/// Gamepad buttons selecting fists, pistol, machinegun and rifle
+#[cfg(feature = "gamepad")]
+const GAMEPAD_WEAPONS: [gilrs::Button; 4] = [
+    gilrs::Button::DPadLeft,
+    gilrs::Button::DPadUp,
+    gilrs::Button::DPadRight,
+    gilrs::Button::DPadDown,
+];
+
+/// Sticks of gamepads moved less than this are considered centered
+#[cfg(feature = "gamepad")]
+const STICK_DEADZONE: f32 = 0.25;
+
+/// Where a player reads its input from
+#[derive(Clone, Copy)]
+pub enum InputSource {
+    Keyboard(&'static KeyboardLayout),
+    /// index of a gamepad in the order they were connected, left stick to move, right stick to aim
+    /// and attack, right trigger to attack and the d-pad to select a weapon
+    Gamepad(usize),
+    /// input is received from a client over the network
+    Network,
+}
+
+/// Connected gamepads, polled once per frame. Without the `gamepad` feature no gamepad is connected.
+#[derive(Default)]
+pub struct Gamepads {
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<gilrs::Gilrs>,
+    /// buttons pressed since the last update, by gamepad id
+    #[cfg(feature = "gamepad")]
+    pressed: Vec<(usize, gilrs::Button)>,
+}
+
+/// Steps of a full turn in which the aim of a player is quantised, such that the input does not
+/// change with every pixel the mouse or the player moves and predicted inputs match more often
+pub const AIM_STEPS: u16 = 256;
+
+/// Input read from an `InputSource` during a single frame
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub locomotion_dir: Vec2,
+    pub attack_dir: Vec2,
+    /// direction the player is aiming in, in `AIM_STEPS` of a full turn, if any
+    pub aim: Option<u16>,
+    /// attack towards `aim`
+    pub fire: bool,
+    /// weapon slot selected during this frame, if any
+    pub weapon: Option<usize>,
+    /// restart the game when every player has died
+    pub restart: bool,
+}
+
+impl PlayerInput {
+    /// Direction the player is aiming in, if any
+    pub fn aim_dir(&self) -> Option<Vec2> {
+        let angle = self.aim? as f32 / AIM_STEPS as f32 * std::f32::consts::TAU;
+        Some(Vec2::new(angle.cos(), angle.sin()))
+    }
+
+    /// Input predicted to follow this input, edge-triggered fields only happen once
+    pub fn predicted(&self) -> PlayerInput {
+        PlayerInput {
+            weapon: None,
+            restart: false,
+            ..self.clone()
+        }
+    }
+}
+
+/// Quantises the direction to `AIM_STEPS` of a full turn
+pub fn quantise_aim(v: Vec2) -> u16 {
+    let turns = f32::atan2(v.y, v.x) / std::f32::consts::TAU;
+    (turns * AIM_STEPS as f32).round().rem_euclid(AIM_STEPS as f32) as u16
+}
+
+impl InputSource {
+    /// Input sources of `count` local players, at least one. A single player uses the keyboard and
+    /// mouse, two players share the keyboard and further players use gamepads.
+    pub fn local_players(count: usize) -> Vec<InputSource> {
+        match count {
+            0 | 1 => vec![InputSource::Keyboard(&SOLO)],
+            _ => [InputSource::Keyboard(&KEYBOARD_LEFT), InputSource::Keyboard(&KEYBOARD_RIGHT)]
+                .into_iter()
+                .chain((0..count - 2).map(InputSource::Gamepad))
+                .collect(),
+        }
+    }
+
+    /// Reads the input, the aim is relative to `pos` of the actor of the player
+    pub fn read(&self, camera: &Camera2D, pos: Option<Vec2>, gamepads: &Gamepads) -> PlayerInput {
+        match self {
+            InputSource::Keyboard(layout) => read_keyboard(layout, camera, pos),
+            InputSource::Gamepad(index) => gamepads.read(*index),
+            InputSource::Network => PlayerInput::default(),
+        }
+    }
+}
+
+impl Gamepads {
+    /// Opens the gamepads, logs why they are not available if they cannot be opened
+    pub fn new() -> Self {
+        #[cfg(feature = "gamepad")]
+        {
+            let gilrs = gilrs::Gilrs::new().map_err(|e| eprintln!("gamepads are not available: {}", e)).ok();
+            Self { gilrs, pressed: Vec::new() }
+        }
+        #[cfg(not(feature = "gamepad"))]
+        Self::default()
+    }
+
+    /// True if gamepads are read, which requires the `gamepad` feature
+    pub fn supported() -> bool {
+        cfg!(feature = "gamepad")
+    }
+
+    /// Polls the events of the gamepads, remembering the buttons pressed since the last update
+    pub fn update(&mut self) {
+        #[cfg(feature = "gamepad")]
+        {
+            self.pressed.clear();
+            let Some(gilrs) = &mut self.gilrs else { return; };
+            while let Some(ev) = gilrs.next_event() {
+                if let gilrs::EventType::ButtonPressed(button, _) = ev.event {
+                    self.pressed.push((ev.id.into(), button));
+                }
+            }
+        }
+    }
+
+    /// Reads the input of the gamepad, the default input if it is not connected
+    #[cfg(not(feature = "gamepad"))]
+    fn read(&self, _index: usize) -> PlayerInput {
+        PlayerInput::default()
+    }
+
+    /// Reads the input of the gamepad, the default input if it is not connected
+    #[cfg(feature = "gamepad")]
+    fn read(&self, index: usize) -> PlayerInput {
+        use gilrs::{Axis, Button};
+
+        let mut input = PlayerInput::default();
+        let Some(gilrs) = &self.gilrs else { return input; };
+        let mut gamepads: Vec<_> = gilrs.gamepads().collect();
+        gamepads.sort_by_key(|x| usize::from(x.0));
+        let Some((id, gamepad)) = gamepads.get(index) else { return input; };
+        let id = usize::from(*id);
+
+        // the y axis of sticks points up, the y axis of the world down
+        let stick = |x: Axis, y: Axis| {
+            let v = Vec2::new(gamepad.value(x), -gamepad.value(y));
+            match v.length() < STICK_DEADZONE {
+                true => Vec2::ZERO,
+                false => v.normalize(),
+            }
+        };
+        input.locomotion_dir = stick(Axis::LeftStickX, Axis::LeftStickY);
+        let aim = stick(Axis::RightStickX, Axis::RightStickY);
+        let trigger = gamepad.is_pressed(Button::RightTrigger2) || gamepad.is_pressed(Button::RightTrigger);
+        if aim != Vec2::ZERO {
+            input.aim = Some(quantise_aim(aim));
+            input.fire = true;
+        } else if input.locomotion_dir != Vec2::ZERO {
+            input.aim = Some(quantise_aim(input.locomotion_dir));
+            input.fire = trigger;
+        }
+
+        let pressed = |button: Button| self.pressed.contains(&(id, button));
+        input.restart = pressed(Button::South) || pressed(Button::Start);
+        for (slot, button) in GAMEPAD_WEAPONS.iter().enumerate() {
+            if pressed(*button) {
+                input.weapon = Some(slot);
+            }
+        }
+
+        input
+    }
+}
+
+fn read_keyboard(layout: &KeyboardLayout, camera: &Camera2D, pos: Option<Vec2>) -> PlayerInput {
+    let mut input = PlayerInput::default();
+    let mut d = Vec2::new(0.0, 0.0);
+    if is_key_down(layout.left) {
+        d.x = -1.0;
+    }
+    if is_key_down(layout.right) {
+        d.x = 1.0;
+    }
+    if is_key_down(layout.up) {
+        d.y = -1.0;
+    }
+    if is_key_down(layout.down) {
+        d.y = 1.0;
+    }
+    input.locomotion_dir = d.normalize_or_zero();
+
+    let key_down = |key: Option<KeyCode>| key.map(is_key_down).unwrap_or_default();
+    let mut attack_dir = Vec2::new(0.0, 0.0);
+    if key_down(layout.attack_left) {
+        attack_dir.x = -1.0;
+    }
+    if key_down(layout.attack_right) {
+        attack_dir.x = 1.0;
+    }
+    if key_down(layout.attack_up) {
+        attack_dir.y = -1.0;
+    }
+    if key_down(layout.attack_down) {
+        attack_dir.y = 1.0;
+    }
+    input.attack_dir = attack_dir.normalize_or_zero();
+
+    if layout.mouse && attack_dir.length() == 0.0 {
+        let m = mouse_position();
+        let aim = camera.screen_to_world(m.into());
+        input.aim = pos.map(|pos| quantise_aim(aim - pos));
+        input.fire = is_mouse_button_down(MouseButton::Left);
+    }
+
+    input.restart = is_key_pressed(KeyCode::Space) || (layout.mouse && is_mouse_button_pressed(MouseButton::Left));
+
+    for (slot, key) in layout.weapons.iter().enumerate() {
+        if is_key_pressed(*key) {
+            input.weapon = Some(slot);
+        }
+    }
+
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearby_aims_are_equal() {
+        let a = PlayerInput { aim: Some(quantise_aim(Vec2::new(10.0, 1.0))), ..Default::default() };
+        let b = PlayerInput { aim: Some(quantise_aim(Vec2::new(10.0, 1.01))), ..Default::default() };
+        assert!(a == b);
+        let dir = a.aim_dir().unwrap();
+        assert!(dir.angle_between(Vec2::new(10.0, 1.0)).abs() < std::f32::consts::TAU / AIM_STEPS as f32);
+        assert_eq!(quantise_aim(Vec2::new(1.0, -0.001)), 0);
+    }
+
+    #[test]
+    fn players_beyond_the_keyboard_use_gamepads() {
+        assert!(matches!(InputSource::local_players(1)[..], [InputSource::Keyboard(_)]));
+        let gamepads: Vec<usize> = InputSource::local_players(4).iter().filter_map(|x| match x {
+            InputSource::Gamepad(index) => Some(*index),
+            _ => None,
+        }).collect();
+        assert_eq!(gamepads, vec![0, 1]);
+        assert!(matches!(InputSource::local_players(4)[..2], [InputSource::Keyboard(_), InputSource::Keyboard(_)]));
+    }
+
+    #[test]
+    fn predictions_do_not_repeat_one_shot_fields() {
+        let input = PlayerInput { fire: true, weapon: Some(2), restart: true, ..Default::default() };
+        let predicted = input.predicted();
+        assert!(predicted.fire);
+        assert_eq!(predicted.weapon, None);
+        assert!(!predicted.restart);
+    }
+}
@@ -9,18 +9,248 @@ mod metadata;
 pub use metadata::*;
 mod snapshot;
 pub use snapshot::*;
+mod input;
+pub use input::*;
+mod net;
+pub use net::*;
+mod delta;
+pub use delta::*;
+mod rollback;
+pub use rollback::*;
+mod trace;
+pub use trace::*;
+mod save;
+pub use save::*;
+mod hotreload;
+pub use hotreload::*;
+mod content;
+pub use content::*;
+mod atlas;
+pub use atlas::*;
+mod render;
+pub use render::*;
+mod camera;
+pub use camera::*;
+#[cfg(test)]
+mod golden;
 
-#[macroquad::main("Guy vs Zombies!")]
-async fn main() {
-    let mut context = Context::default();
-    context.metadata = Metadata::new().await;
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match (args.first().map(|x| x.as_str()), args.get(1), args.get(2)) {
+        (Some("compare-traces"), Some(a), Some(b)) => std::process::exit(compare_trace_files(a, b)),
+        (Some("compare-traces"), _, _) => usage("compare-traces <first> <second>"),
+        (Some("convert-save"), Some(input), Some(output)) => std::process::exit(convert_save(input, output)),
+        (Some("convert-save"), _, _) => usage("convert-save <input> <output>, saves ending in .ron are text"),
+        (Some("check-assets"), Some(flag), Some(mods)) if flag == "--mods" => std::process::exit(check_assets(&parse_mods(mods))),
+        (Some("check-assets"), None, _) => std::process::exit(check_assets(&[])),
+        (Some("check-assets"), _, _) => usage("check-assets [--mods <mod,...>]"),
+        (Some("pack-atlas"), Some(images), Some(atlas)) => std::process::exit(pack_atlas_file(images, atlas)),
+        (Some("pack-atlas"), _, _) => usage("pack-atlas <images.toml> <atlas.png>, prints the packed images.toml"),
+        (Some("render-save"), Some(save), Some(output)) => std::process::exit(render_save(save, output, &args[3..])),
+        (Some("render-save"), _, _) => usage("render-save <save> <output.png> [--size <width>x<height>] [--mods <mod,...>]"),
+        _ => macroquad::Window::new("Guy vs Zombies!", run(args)),
+    }
+}
+
+fn usage(command: &str) {
+    eprintln!("usage: guyvszombies {}", command);
+    std::process::exit(2);
+}
+
+/// Ids of the mods in a comma separated list, in the order they are enabled
+fn parse_mods(list: &str) -> Vec<String> {
+    list.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_owned()).collect()
+}
+
+/// Prints every problem found in the assets and mods, returns the exit code
+fn check_assets(mods: &[String]) -> i32 {
+    let diagnostics = Metadata::check(mods);
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics.iter().filter(|x| x.severity == Severity::Error).count();
+    println!("{} errors, {} warnings", errors, diagnostics.len() - errors);
+    match errors {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+/// Packs the loose images of an images.toml into an atlas and prints the new images.toml, returns the exit code
+fn pack_atlas_file(images: &str, atlas: &str) -> i32 {
+    match pack_images_file(images, atlas) {
+        Ok(packed) => {
+            print!("{}", packed);
+            0
+        }
+        Err(e) => {
+            eprintln!("failed to pack atlas: {}", e);
+            1
+        }
+    }
+}
+
+/// Renders a save to a PNG with the software renderer, without a window, returns the exit code
+fn render_save(path: &str, output: &str, args: &[String]) -> i32 {
+    let mut size = (1280, 720);
+    let mut mods = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--size" => {
+                let parsed = args.next().and_then(|x| x.split_once('x')).and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+                size = parsed.unwrap_or(size);
+            }
+            "--mods" => mods = args.next().map(|x| parse_mods(x)).unwrap_or_default(),
+            _ => {}
+        }
+    }
+    let metadata = match Metadata::from_disk(&mods, false) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let save = match read_save_file(path) {
+        Ok(save) => save,
+        Err(e) => {
+            eprintln!("failed to load {}: {}", path, e);
+            return 1;
+        }
+    };
+    let (state, warnings) = save.snapshot.load_snapshot(&metadata);
+    for warning in warnings.iter() {
+        eprintln!("warning: {}: {}", path, warning);
+    }
+    let mut context = Context { metadata, state, ..Default::default() };
+    let mut renderer = SoftwareRenderer::new(size.0, size.1);
+    context.draw_list.begin(renderer.screen_size());
+    systems::draw_state(&mut context, true);
+    renderer.render(&context.draw_list.commands);
+    match renderer.save_png(output) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Converts a save between the binary and the text format, returns the exit code
+fn convert_save(input: &str, output: &str) -> i32 {
+    let result = read_save_file(input).and_then(|save| write_save_file(output, &save));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("failed to convert {}: {}", input, e);
+            1
+        }
+    }
+}
+
+/// Prints the first tick where the traces diverge, returns the exit code
+fn compare_trace_files(a: &str, b: &str) -> i32 {
+    let (a, b) = match (read_trace(a), read_trace(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("failed to read trace: {}", e);
+            return 2;
+        }
+    };
+    let Some(divergence) = compare_traces(&a, &b) else {
+        println!("traces match ({} and {} ticks)", a.len(), b.len());
+        return 0;
+    };
+    println!("traces diverge at tick {}", divergence.tick);
+    for difference in divergence.differences.iter() {
+        println!("  {}", difference);
+    }
+    1
+}
+
+async fn run(args: Vec<String>) {
+    let mut players = 1;
+    let mut host = None;
+    let mut connect = None;
+    let mut peer = None;
+    let mut player = 0;
+    let mut conditions = NetConditions::default();
+    let mut trace = None;
+    let mut replay = None;
+    let mut load = None;
+    let mut mods = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--players" => players = args.next().and_then(|x| x.parse().ok()).unwrap_or(players),
+            "--host" => host = args.next(),
+            "--connect" => connect = args.next(),
+            "--peer" => peer = args.next().zip(args.next()),
+            "--player" => player = args.next().and_then(|x| x.parse().ok()).unwrap_or(player),
+            "--latency" => conditions.latency = args.next().and_then(|x| x.parse().ok()).unwrap_or(0.0) / 1000.0,
+            "--trace" => trace = args.next(),
+            "--replay" => replay = args.next(),
+            "--load" => load = args.next(),
+            "--mods" => mods = args.next().map(|x| parse_mods(&x)).unwrap_or_default(),
+            "--packet-loss" => conditions.packet_loss = args.next().and_then(|x| x.parse().ok()).unwrap_or(0.0),
+            _ => {}
+        }
+    }
+    let mut inputs = InputSource::local_players(players);
+    if players > 2 && !Gamepads::supported() {
+        eprintln!("warning: players beyond the second use gamepads, which require the gamepad feature");
+    }
+    if load.is_some() && (host.is_some() || connect.is_some() || peer.is_some()) {
+        eprintln!("warning: saves are not loaded while networked");
+    }
+    let net = match (host, connect, peer) {
+        (Some(addr), _, _) => Some(Net::Server(Server::new(&addr, conditions).expect("failed to host"))),
+        (None, Some(addr), _) => Some(Net::Client(Client::new(&addr, conditions).expect("failed to connect"))),
+        (None, None, Some((addr, remote))) => {
+            let peer = Peer::new(&addr, &remote, player, conditions).expect("failed to start peer");
+            inputs = vec![InputSource::Network; PEERS];
+            inputs[peer.player] = InputSource::Keyboard(&SOLO);
+            Some(Net::Peer(peer))
+        }
+        _ => None,
+    };
+    let metadata = match Metadata::new(&mods).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for warning in metadata.warnings.iter() {
+        eprintln!("{}", warning);
+    }
+    let mut context = Context {
+        watcher: MetadataWatcher::new(&metadata),
+        metadata,
+        inputs,
+        gamepads: Gamepads::new(),
+        net,
+        trace: trace.map(|x| TraceWriter::create(&x).expect("failed to create trace")),
+        replay: replay.map(|x| Replay::load(&x).expect("failed to load replay")),
+        ..Default::default()
+    };
     systems::once(&mut context);
+    if let Some(path) = load.filter(|_| context.net.is_none()) {
+        let save = read_save_file(&path).expect("failed to read save");
+        let (state, warnings) = save.snapshot.load_snapshot(&context.metadata);
+        for warning in warnings.iter() {
+            eprintln!("warning: {}: {}", path, warning);
+        }
+        context.state = state;
+        context.playtime = save.info.playtime;
+    }
     set_mouse_cursor(miniquad::CursorIcon::Crosshair);
+    let mut renderer = MacroquadRenderer;
     loop {
+        context.draw_list.begin(renderer.screen_size());
         systems::tick(&mut context);
-        set_default_camera();
-        let font_size = 24.0;
-        draw_text(&format!("{}", get_fps()), font_size, font_size, font_size, RED);
+        renderer.render(&context.draw_list.commands);
         next_frame().await
     }
 }
\ No newline at end of file
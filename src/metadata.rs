@@ -1,46 +1,289 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    rc::Rc,
+};
 
-use glam::Vec2;
+use glam::{Vec2, Vec4};
 use macroquad::{
     file::load_file,
-    texture::{load_texture, Texture2D},
+    math::Rect,
+    texture::{Image, Texture2D},
 };
+use serde::{Deserialize, Serialize};
 use toml::{Table, Value};
 
+use crate::{ContentPack, BASE_ROOT, MODS_DIR};
+
 type InfoCollection<T> = HashMap<String, Rc<T>>;
 
+const IMAGES_FILE: &str = "images.toml";
+const EFFECTS_FILE: &str = "effects.toml";
+const WEAPONS_FILE: &str = "weapons.toml";
+const ACTORS_FILE: &str = "actors.toml";
+
+/// Frames per second of clips which do not define `fps`
+const DEFAULT_FPS: f32 = 10.0;
+
 pub struct ImageInfo {
     pub name: String,
+    /// path of the image file, including the directory of its content pack
     pub path: String,
-    pub texture: Texture2D,
+    /// texture of the file, shared by every image of the same file, none when loaded without a GPU
+    pub texture: Option<Texture2D>,
+    /// pixels of the file, used to draw without a GPU
+    pub pixels: Rc<Image>,
+    /// region of the texture of every frame of a sprite sheet, in pixels
+    pub frames: Vec<Rect>,
 }
 
+/// Frame of an image, referenced as `image` or `sheet:index`
 #[derive(Clone)]
 pub struct ImageIndex {
     pub image: Rc<ImageInfo>,
     pub frame: u16,
 }
 
+impl fmt::Debug for ImageIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.image.name, self.frame)
+    }
+}
+
+impl ImageIndex {
+    /// Region of the texture to draw
+    pub fn source(&self) -> Rect {
+        self.image.frames.get(self.frame as usize).copied().unwrap_or_default()
+    }
+}
+
+/// Named animation clip of an actor
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Clip {
+    #[default]
+    Idle,
+    Walk,
+    Attack,
+    Pain,
+    Die,
+}
+
+impl Clip {
+    pub const ALL: [Clip; 5] = [Clip::Idle, Clip::Walk, Clip::Attack, Clip::Pain, Clip::Die];
+
+    /// Key of the clip in actors.toml
+    pub fn key(self) -> &'static str {
+        match self {
+            Clip::Idle => "idle",
+            Clip::Walk => "walk",
+            Clip::Attack => "attack",
+            Clip::Pain => "pain",
+            Clip::Die => "die",
+        }
+    }
+
+    /// Key of the frames used when the clip is not defined, from before actors had clips
+    fn legacy_key(self) -> Option<&'static str> {
+        match self {
+            Clip::Idle => Some("frames"),
+            Clip::Walk => Some("locomotion_frames"),
+            Clip::Die => Some("dead_frames"),
+            Clip::Attack | Clip::Pain => None,
+        }
+    }
+}
+
+/// What a clip does when it reaches its last frame
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Playback {
+    /// start over from the first frame
+    #[default]
+    Loop,
+    /// hold the last frame
+    Once,
+}
+
+/// Event emitted when a clip reaches a frame, such as `projectile` or `footstep`
+#[derive(Clone)]
+pub struct FrameEvent {
+    pub frame: u16,
+    pub name: String,
+}
+
+#[derive(Clone, Default)]
+pub struct AnimationClip {
+    pub frames: Vec<ImageIndex>,
+    /// frames shown per second
+    pub fps: f32,
+    pub playback: Playback,
+    pub events: Vec<FrameEvent>,
+}
+
+impl AnimationClip {
+    /// Number of frames the clip has advanced `time` seconds after it was started, including loops
+    fn frames_played(&self, time: f32) -> i64 {
+        (time * self.fps).floor() as i64
+    }
+
+    /// Index of the frame shown `time` seconds after the clip was started
+    pub fn frame_index(&self, time: f32) -> usize {
+        let played = self.frames_played(time).max(0) as usize;
+        match self.playback {
+            Playback::Loop => played % self.frames.len().max(1),
+            Playback::Once => played.min(self.frames.len().saturating_sub(1)),
+        }
+    }
+
+    /// Frame shown `time` seconds after the clip was started
+    pub fn frame(&self, time: f32) -> Option<&ImageIndex> {
+        self.frames.get(self.frame_index(time))
+    }
+
+    /// Whether every frame has been shown once `time` seconds after the clip was started
+    pub fn is_finished(&self, time: f32) -> bool {
+        self.frames_played(time) >= self.frames.len() as i64
+    }
+
+    /// Events of the frames reached when advancing from `from` to `to` seconds, `None` if the clip
+    /// was just started such that the events of the first frame are included
+    pub fn events(&self, from: Option<f32>, to: f32) -> Vec<&str> {
+        let mut events = Vec::new();
+        if self.frames.is_empty() {
+            return events;
+        }
+        let start = from.map(|x| self.frames_played(x)).unwrap_or(-1);
+        for played in start + 1..=self.frames_played(to) {
+            let frame = match self.playback {
+                Playback::Loop => played as usize % self.frames.len(),
+                Playback::Once if played as usize >= self.frames.len() => break,
+                Playback::Once => played as usize,
+            };
+            events.extend(self.events.iter().filter(|x| x.frame as usize == frame).map(|x| x.name.as_str()));
+        }
+        events
+    }
+
+    pub fn has_event(&self, name: &str) -> bool {
+        self.events.iter().any(|x| x.name == name)
+    }
+}
+
+impl Default for EffectInfo {
+    /// Effect which lasts no time and has no effect
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            duration: 0.0,
+            damage_per_second: 0.0,
+            speed_multiplier: 1.0,
+            stun: false,
+            stacking: Stacking::default(),
+            max_stacks: 1,
+            tint: Vec4::ONE,
+        }
+    }
+}
+
+/// How a status effect behaves when applied to an actor which already has it
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Stacking {
+    /// restart the duration of the existing effect
+    #[default]
+    Refresh,
+    /// add a stack (up to `max_stacks`) and restart the duration
+    Stack,
+    /// keep the existing effect untouched
+    Ignore,
+}
+
+#[derive(Clone)]
+pub struct EffectInfo {
+    pub name: String,
+    /// how long the effect lasts after being applied
+    pub duration: f32,
+    /// damage dealt per second for each stack
+    pub damage_per_second: f32,
+    /// multiplied onto the speed of the actor while the effect is active
+    pub speed_multiplier: f32,
+    /// actors with the effect are not able to attack
+    pub stun: bool,
+    pub stacking: Stacking,
+    pub max_stacks: u32,
+    /// multiplied onto the color of the actor while the effect is active
+    pub tint: Vec4,
+}
+
 #[derive(Clone, Default)]
 pub struct WeaponInfo {
     pub name: String,
+    /// name shown to the players, defaults to `name`
+    pub display_name: String,
     pub rate_of_fire: f32,
+    /// the first frame is shown while idle, the others are played after each shot
     pub frames: Vec<ImageIndex>,
+    /// seconds the firing frames and muzzle flash are shown after each shot
+    pub firing_time: f32,
+    /// frames of the muzzle flash, played like the firing frames
+    pub muzzle_flash: Vec<ImageIndex>,
+    /// size of the muzzle flash sprite
+    pub muzzle_flash_size: f32,
+    /// color of the light at the muzzle after each shot, no light if fully transparent
+    pub muzzle_light: Vec4,
+    pub muzzle_light_radius: f32,
+    /// trauma added to the camera when a player fires, from 0 to 1
+    pub shake: f32,
     pub damage: [f32; 2],
     pub mount_offset: f32,
     pub muzzle_offset: f32,
     pub spread: f32,
     pub projectile: String,
-    pub range:f32
+    pub range:f32,
+    /// effects applied to actors hit by projectiles fired by this weapon
+    pub effects: Vec<Rc<EffectInfo>>,
+    /// impulse applied to actors hit by projectiles fired by this weapon
+    pub knockback: f32,
+    /// sweep a cone in front of the hand instead of firing a projectile
+    pub melee: bool,
+    /// reach of the melee cone measured from the hand
+    pub melee_reach: f32,
+    /// angle of the melee cone in radians
+    pub melee_arc: f32,
+    /// time from starting a melee attack until it hits
+    pub windup: f32,
+    /// time after a melee attack has hit before another can be started
+    pub recovery: f32,
+}
+
+impl WeaponInfo {
+    /// Frame of the weapon, `firing` is the time since the last shot while the firing frames are shown
+    pub fn frame(&self, firing: Option<f32>) -> Option<&ImageIndex> {
+        match firing {
+            Some(time) if self.frames.len() > 1 => self.frames.get(1 + self.firing_index(time, self.frames.len() - 1)),
+            _ => self.frames.first(),
+        }
+    }
+
+    /// Frame of the muzzle flash `time` seconds after a shot
+    pub fn muzzle_flash_frame(&self, time: f32) -> Option<&ImageIndex> {
+        self.muzzle_flash.get(self.firing_index(time, self.muzzle_flash.len()))
+    }
+
+    /// Index into `count` frames played once during `firing_time`
+    fn firing_index(&self, time: f32, count: usize) -> usize {
+        let index = (time / self.firing_time * count as f32) as usize;
+        index.min(count.saturating_sub(1))
+    }
 }
 
 #[derive(Clone)]
 pub struct ActorInfo {
     pub name: String,
-    pub frames: Vec<ImageIndex>,
-    pub locomotion_frames: Vec<ImageIndex>,
-    pub dead_frames: Vec<ImageIndex>,
+    /// animation clips, indexed by `Clip`
+    pub clips: Vec<AnimationClip>,
     pub bot: bool,
+    /// melee attacks do not hit actors of the same team, actors without a team hit everyone
+    pub team: String,
     pub speed: f32,
     pub radius: f32,
     pub missile: bool,
@@ -59,35 +302,337 @@ pub struct ActorInfo {
     /// despawn after actor has existed for max_age
     pub max_age: f32,
     /// actors starts with this velocity
-    pub velocity:f32
+    pub velocity:f32,
+    /// effects applied to actors hit by this missile
+    pub effects: Vec<Rc<EffectInfo>>,
+    /// impulse applied to actors hit by this missile
+    pub knockback: f32,
+    /// scales down the knockback impulses received by the actor
+    pub mass: f32,
+    /// number of shootable actors the missile passes through before being despawned
+    pub pierce_count: u32,
+    /// damage multiplier applied for every actor pierced by the missile
+    pub pierce_falloff: f32,
+    /// number of times the missile bounces off bounds and non-shootable actors
+    pub bounce_count: u32,
+    /// despawn the corpse after the actor has been dead for corpse_lifetime (unless zero)
+    pub corpse_lifetime: f32,
+    /// corpses fade out during the last corpse_fade seconds of their lifetime
+    pub corpse_fade: f32,
+    /// actor is gibbed when killed with at least this much overkill damage (unless zero)
+    pub gib_threshold: f32,
+    /// particle actor spawned when gibbed
+    pub gibs: String,
+    pub gib_count: u32,
+}
+
+impl ActorInfo {
+    pub fn clip(&self, clip: Clip) -> &AnimationClip {
+        &self.clips[clip as usize]
+    }
+}
+
+/// How bad a problem found while loading metadata is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// the metadata can be used, but is likely not what was intended
+    Warning,
+    Error,
+}
+
+/// Problem found while loading metadata
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    /// table the problem was found in, if any
+    pub table: Option<String>,
+    /// key of the table the problem was found in, if any
+    pub key: Option<String>,
+    pub message: String,
+}
+
+/// Every problem found while loading metadata, returned when at least one is an error
+#[derive(Debug, Default)]
+pub struct MetadataError {
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Default)]
 pub struct Metadata {
     pub images: InfoCollection<ImageInfo>,
+    pub effects: InfoCollection<EffectInfo>,
     pub weapons: InfoCollection<WeaponInfo>,
     pub actors: InfoCollection<ActorInfo>,
+    /// sorted names of the weapons, the index of a name is its compact id
+    pub weapon_ids: Vec<String>,
+    /// sorted names of the actors, the index of a name is its compact id
+    pub actor_ids: Vec<String>,
+    /// problems found while loading which were not errors
+    pub warnings: Vec<Diagnostic>,
+    /// content packs the metadata was loaded from, starting with the base game
+    pub packs: Vec<ContentPack>,
 }
 
-fn get_f32(prop: &str, props: &Value) -> Option<f32> {
-    let Some(v) = props.get(prop) else {
-        return None;
-    };
-    v.as_float()
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.file)?,
+            Severity::Error => write!(f, "error: {}", self.file)?,
+        }
+        if let Some(table) = &self.table {
+            write!(f, " [{}]", table)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, " {}", key)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// Collects the problems found while loading metadata
+#[derive(Default)]
+struct Diagnostics {
+    list: RefCell<Vec<Diagnostic>>,
+}
+
+impl Diagnostics {
+    fn push(&self, severity: Severity, file: &str, table: Option<&str>, key: Option<&str>, message: String) {
+        self.list.borrow_mut().push(Diagnostic {
+            severity,
+            file: file.to_owned(),
+            table: table.map(|x| x.to_owned()),
+            key: key.map(|x| x.to_owned()),
+            message,
+        });
+    }
+
+    fn error(&self, file: &str, table: Option<&str>, key: Option<&str>, message: String) {
+        self.push(Severity::Error, file, table, key, message);
+    }
+}
+
+/// Properties of a table being loaded.
+///
+/// Remembers the keys which are read, such that unknown keys can be reported. Problems with keys
+/// inherited through `extends` are reported against the table the key was defined in.
+struct Props<'a> {
+    file: &'a str,
+    table: &'a str,
+    value: &'a Value,
+    diagnostics: &'a Diagnostics,
+    known: &'a RefCell<HashSet<String>>,
+    /// merged tables and the table each key of this table was defined in, if extended
+    origins: Option<(&'a Layered, &'a HashMap<String, String>)>,
+}
+
+impl<'a> Props<'a> {
+    /// Value of the key, if present
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.known.borrow_mut().insert(key.to_owned());
+        self.value.get(key)
+    }
+
+    fn error(&self, key: &str, message: String) {
+        let (file, table) = self.origin(key);
+        self.diagnostics.error(file, Some(table), Some(key), message);
+    }
+
+    /// File and table the key was defined in
+    fn origin(&self, key: &str) -> (&'a str, &'a str) {
+        let Some((layered, origins)) = self.origins else {
+            return (self.file, self.table);
+        };
+        match origins.get(key) {
+            Some(table) => (layered.key_file(table, key), table.as_str()),
+            None => (self.file, self.table),
+        }
+    }
+
+    /// Reports that the key has a value of the wrong type
+    fn mismatch(&self, key: &str, expected: &str, found: &Value) {
+        self.error(key, format!("expected {}, found {}", expected, found.type_str()));
+    }
+}
+
+/// Tables of one kind of file, merged from every content pack
+struct Layered {
+    /// name of the file in every pack
+    name: &'static str,
+    table: Table,
+    /// file each table was last defined in, used to report problems
+    files: HashMap<String, String>,
+    /// file each key of a table was last defined in
+    key_files: HashMap<(String, String), String>,
+}
+
+impl Layered {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            table: Table::default(),
+            files: HashMap::new(),
+            key_files: HashMap::new(),
+        }
+    }
+
+    /// File the table was last defined in
+    fn file(&self, table: &str) -> &str {
+        self.files.get(table).map(|x| x.as_str()).unwrap_or(self.name)
+    }
+
+    /// File the key of the table was last defined in
+    fn key_file(&self, table: &str, key: &str) -> &str {
+        let file = self.key_files.get(&(table.to_owned(), key.to_owned()));
+        file.map(|x| x.as_str()).unwrap_or_else(|| self.file(table))
+    }
+
+    /// Adds the tables of a file, tables which already exist are merged key by key
+    fn merge(&mut self, file: &str, table: Table) {
+        for (name, value) in table {
+            for key in value.as_table().into_iter().flat_map(|x| x.keys()) {
+                self.key_files.insert((name.clone(), key.clone()), file.to_owned());
+            }
+            match (self.table.get_mut(&name), value) {
+                (Some(Value::Table(existing)), Value::Table(value)) => existing.extend(value),
+                (_, value) => {
+                    self.table.insert(name.clone(), value);
+                }
+            }
+            self.files.insert(name, file.to_owned());
+        }
+    }
+}
+
+/// Every kind of file merged from the enabled content packs
+struct Sources {
+    packs: Vec<ContentPack>,
+    images: Layered,
+    effects: Layered,
+    weapons: Layered,
+    actors: Layered,
+}
+
+/// Tables after extension together with the table each of their keys was defined in, by table and key
+struct Extended {
+    table: Table,
+    origins: HashMap<String, HashMap<String, String>>,
+}
+
+/// Tables of a file after extension, which the loaders iterate over
+struct Tables<'a> {
+    layered: &'a Layered,
+    table: &'a Table,
+    origins: Option<&'a HashMap<String, HashMap<String, String>>>,
+    diagnostics: &'a Diagnostics,
+    known: RefCell<HashSet<String>>,
+}
+
+impl<'a> Tables<'a> {
+    fn new(layered: &'a Layered, table: &'a Table, diagnostics: &'a Diagnostics) -> Self {
+        Self {
+            layered,
+            table,
+            origins: None,
+            diagnostics,
+            known: RefCell::new(HashSet::from(["extends".to_owned()])),
+        }
+    }
+
+    fn extended(layered: &'a Layered, extended: &'a Extended, diagnostics: &'a Diagnostics) -> Self {
+        Self {
+            origins: Some(&extended.origins),
+            ..Self::new(layered, &extended.table, diagnostics)
+        }
+    }
+
+    /// File and table the key of the extended table was defined in
+    fn origin(&self, table: &'a str, key: &str) -> (&'a str, &'a str) {
+        match self.origins.and_then(|x| x.get(table)).and_then(|x| x.get(key)) {
+            Some(origin) => (self.layered.key_file(origin, key), origin.as_str()),
+            None => (self.layered.file(table), table),
+        }
+    }
+
+    /// Properties of every table, reports values which are not tables
+    fn iter(&self) -> impl Iterator<Item = (&'a String, Props<'_>)> {
+        self.table.iter().filter_map(|(name, value)| {
+            let file = self.layered.file(name);
+            if !value.is_table() {
+                self.diagnostics.error(file, Some(name), None, "expected a table".to_owned());
+                return None;
+            }
+            let props = Props {
+                file,
+                table: name,
+                value,
+                diagnostics: self.diagnostics,
+                known: &self.known,
+                origins: self.origins.and_then(|x| x.get(name)).map(|x| (self.layered, x)),
+            };
+            Some((name, props))
+        })
+    }
+
+    /// Warns about the keys of the tables before extension which were never read by the loader
+    fn warn_unknown_keys(&self) {
+        let known = self.known.borrow();
+        for (name, value) in self.layered.table.iter() {
+            let Some(table) = value.as_table() else { continue; };
+            for key in table.keys() {
+                if !known.contains(key) {
+                    let file = self.layered.key_file(name, key);
+                    self.diagnostics.push(Severity::Warning, file, Some(name), Some(key), "unknown key".to_owned());
+                }
+            }
+        }
+    }
+}
+
+/// Sorted names of the collection, such that ids are equal when metadata is equal
+fn ids<T>(collection: &InfoCollection<T>) -> Vec<String> {
+    let mut ids: Vec<String> = collection.keys().cloned().collect();
+    ids.sort();
+    ids
+}
+
+fn get_f32(prop: &str, props: &Props) -> Option<f32> {
+    let v = props.get(prop)?;
+    let res = v
+        .as_float()
         .or(v.as_integer().map(|x| x as f64))
-        .map(|x| x as f32)
+        .map(|x| x as f32);
+    if res.is_none() {
+        props.mismatch(prop, "a number", v);
+    }
+    res
 }
-fn get_array_string(prop: &str, props: &Value) -> Option<Vec<String>> {
-    let Some(v) = props.get(prop) else {
-        return None;
-    };
+
+fn get_array_string(prop: &str, props: &Props) -> Option<Vec<String>> {
+    let v = props.get(prop)?;
 
     let mut res = Vec::new();
-    let Some(v) = v.as_array() else {
+    let Some(array) = v.as_array() else {
+        props.mismatch(prop, "an array of strings", v);
         return None;
     };
-    for v in v.iter() {
+    for v in array.iter() {
         let Some(v) = v.as_str() else {
+            props.mismatch(prop, "an array of strings", v);
             return None;
         };
         res.push(v.to_string());
@@ -96,144 +641,571 @@ fn get_array_string(prop: &str, props: &Value) -> Option<Vec<String>> {
     Some(res)
 }
 
-fn get_bool(prop: &str, props: &Value) -> Option<bool> {
-    let Some(v) = props.get(prop) else {
-        return None;
-    };
-    v.as_bool()
+fn get_bool(prop: &str, props: &Props) -> Option<bool> {
+    let v = props.get(prop)?;
+    let res = v.as_bool();
+    if res.is_none() {
+        props.mismatch(prop, "a boolean", v);
+    }
+    res
 }
 
-fn get_str<'a>(prop: &'a str, props: &'a Value) -> Option<&'a str> {
-    let Some(v) = props.get(prop) else {
-        return None;
-    };
-    v.as_str()
+fn get_str<'a>(prop: &str, props: &Props<'a>) -> Option<&'a str> {
+    let v = props.get(prop)?;
+    let res = v.as_str();
+    if res.is_none() {
+        props.mismatch(prop, "a string", v);
+    }
+    res
 }
 
-fn get_array_f32<'a>(prop: &'a str, props: &'a Value) -> Option<Vec<f32>> {
-    let Some(v) = props.get(prop) else {
-        return None;
-    };
-    let Some(v) = v.as_array() else {
+fn get_array_f32(prop: &str, props: &Props) -> Option<Vec<f32>> {
+    let v = props.get(prop)?;
+    let Some(array) = v.as_array() else {
+        props.mismatch(prop, "an array of numbers", v);
         return None;
     };
     let mut vec = Vec::new();
-    for v in v.iter() {
+    for v in array.iter() {
         match v {
             Value::Integer(i) => vec.push(*i as f32),
             Value::Float(f) => vec.push(*f as f32),
-            _ => {}
+            v => {
+                props.mismatch(prop, "an array of numbers", v);
+                return None;
+            }
         }
     }
     Some(vec)
 }
 
-fn get_vec2<'a>(prop: &'a str, props: &'a Value) -> Option<Vec2> {
-    let Some(v) = get_array_f32(prop, props) else {
+/// Numbers of an array property which must have one of the lengths
+fn get_array_f32_len(prop: &str, props: &Props, lengths: &[usize]) -> Option<Vec<f32>> {
+    let v = get_array_f32(prop, props)?;
+    if !lengths.contains(&v.len()) {
+        let lengths: Vec<String> = lengths.iter().map(|x| x.to_string()).collect();
+        props.error(prop, format!("expected {} numbers, found {}", lengths.join(" or "), v.len()));
         return None;
-    };
-    if v.len() == 2 {
-        return Some(Vec2::new(v[0], v[1]));
     }
+    Some(v)
+}
 
-    None
+fn get_vec2(prop: &str, props: &Props) -> Option<Vec2> {
+    let v = get_array_f32_len(prop, props, &[2])?;
+    Some(Vec2::new(v[0], v[1]))
 }
 
-fn get_tuple_f32<'a>(prop: &'a str, props: &'a Value) -> Option<(f32, f32)> {
-    let Some(v) = get_array_f32(prop, props) else {
+fn get_vec4(prop: &str, props: &Props) -> Option<Vec4> {
+    let v = get_array_f32_len(prop, props, &[3, 4])?;
+    Some(Vec4::new(v[0], v[1], v[2], v.get(3).copied().unwrap_or(1.0)))
+}
+
+fn get_tuple_f32(prop: &str, props: &Props) -> Option<(f32, f32)> {
+    let v = get_array_f32_len(prop, props, &[2])?;
+    Some((v[0], v[1]))
+}
+
+/// Regions of an array of `[x, y, width, height]` arrays
+fn get_rects(prop: &str, props: &Props) -> Option<Vec<Rect>> {
+    let v = props.get(prop)?;
+    let Some(array) = v.as_array() else {
+        props.mismatch(prop, "an array of [x, y, width, height] arrays", v);
         return None;
     };
-    if v.len() == 2 {
-        return Some((v[0], v[1]));
+    let mut rects = Vec::new();
+    for v in array.iter() {
+        let number = |v: &Value| match v {
+            Value::Integer(i) => Some(*i as f32),
+            Value::Float(f) => Some(*f as f32),
+            _ => None,
+        };
+        let rect: Option<Vec<f32>> = v.as_array().filter(|x| x.len() == 4).and_then(|x| x.iter().map(number).collect());
+        let Some(rect) = rect else {
+            props.mismatch(prop, "an array of [x, y, width, height] arrays", v);
+            return None;
+        };
+        rects.push(Rect::new(rect[0], rect[1], rect[2], rect[3]));
     }
-
-    None
+    Some(rects)
 }
 
-fn get_frames<'a>(
-    prop: &'a str,
-    props: &'a Value,
+fn get_frames(
+    prop: &str,
+    props: &Props,
     images: &InfoCollection<ImageInfo>,
 ) -> Vec<ImageIndex> {
     let mut frames = Vec::new();
     if let Some(props_frames) = get_array_string(prop, props) {
         for frame in props_frames.iter() {
+            let (name, index) = match frame.rsplit_once(':') {
+                Some((name, index)) => match index.parse::<u16>() {
+                    Ok(index) => (name, index),
+                    Err(_) => {
+                        props.error(prop, format!("invalid frame index in \"{}\"", frame));
+                        continue;
+                    }
+                },
+                None => (frame.as_str(), 0),
+            };
+            let Some(image) = images.get(name) else {
+                props.error(prop, format!("unknown image \"{}\"", name));
+                continue;
+            };
+            if index as usize >= image.frames.len() {
+                props.error(prop, format!("image \"{}\" has {} frames, found \"{}\"", name, image.frames.len(), frame));
+                continue;
+            }
             frames.push(ImageIndex {
-                image: images.get(frame).expect("could not find image").clone(),
-                frame: 0,
+                image: image.clone(),
+                frame: index,
             });
         }
     }
     frames
 }
 
-/// Extends the table with content from a 'super' table.
+/// Events of a clip, an array of `{ frame = <index>, event = <name> }` tables
+fn get_frame_events(prop: &str, props: &Props, frame_count: usize) -> Vec<FrameEvent> {
+    let mut events = Vec::new();
+    let Some(v) = props.get(prop) else { return events; };
+    let Some(array) = v.as_array() else {
+        props.mismatch(prop, "an array of { frame, event } tables", v);
+        return events;
+    };
+    for v in array.iter() {
+        let frame = v.get("frame").and_then(|x| x.as_integer());
+        let name = v.get("event").and_then(|x| x.as_str());
+        let (Some(frame), Some(name)) = (frame, name) else {
+            props.mismatch(prop, "an array of { frame, event } tables", v);
+            continue;
+        };
+        if frame < 0 || frame as usize >= frame_count {
+            props.error(prop, format!("event \"{}\" is on frame {}, but the clip has {} frames", name, frame, frame_count));
+            continue;
+        }
+        events.push(FrameEvent {
+            frame: frame as u16,
+            name: name.to_owned(),
+        });
+    }
+    events
+}
+
+/// Animation clip of an actor, a table with `frames`, `fps`, `playback` and `events`.
 ///
-/// Assumes a key called 'extends' which points to a table from which it can extend
-fn extend_table(mut tables: Table) -> Table {
-    let names: Vec<String> = tables.keys().map(|x| x.to_owned()).collect();
-    for name in names.iter() {
-        let mut final_table = Table::default();
-        let org_table = tables.get(name).unwrap().as_table().unwrap();
-        if let Some(extends_table) = org_table
-            .get("extends")
-            .and_then(|x| x.as_str())
-            .and_then(|x| tables.get(x))
-            .and_then(|x| x.as_table())
-        {
-            final_table = extends_table.clone();
+/// Actors without the clip use the frames of its legacy key, if any.
+fn get_clip(clip: Clip, props: &Props, images: &InfoCollection<ImageInfo>) -> AnimationClip {
+    let legacy = clip.legacy_key().map(|x| get_frames(x, props, images)).unwrap_or_default();
+    let mut res = AnimationClip {
+        frames: legacy,
+        fps: DEFAULT_FPS,
+        playback: match clip {
+            Clip::Idle | Clip::Walk => Playback::Loop,
+            Clip::Attack | Clip::Pain | Clip::Die => Playback::Once,
+        },
+        events: Vec::new(),
+    };
+    let Some(value) = props.get(clip.key()) else { return res; };
+    let Some(table) = value.as_table() else {
+        props.mismatch(clip.key(), "a table", value);
+        return res;
+    };
+    // clips inherited through `extends` are reported against the table they were defined in
+    let (file, origin) = props.origin(clip.key());
+    let name = format!("{}.{}", origin, clip.key());
+    let known = RefCell::new(HashSet::new());
+    let clip_props = Props {
+        file,
+        table: &name,
+        value,
+        diagnostics: props.diagnostics,
+        known: &known,
+        origins: None,
+    };
+    res.frames = get_frames("frames", &clip_props, images);
+    match get_f32("fps", &clip_props) {
+        Some(fps) if fps <= 0.0 => clip_props.error("fps", "must be greater than zero".to_owned()),
+        Some(fps) => res.fps = fps,
+        None => {}
+    }
+    match get_str("playback", &clip_props) {
+        Some("loop") => res.playback = Playback::Loop,
+        Some("once") => res.playback = Playback::Once,
+        Some(other) => clip_props.error("playback", format!("expected \"loop\" or \"once\", found \"{}\"", other)),
+        None => {}
+    }
+    res.events = get_frame_events("events", &clip_props, res.frames.len());
+    for key in table.keys() {
+        if !known.borrow().contains(key) {
+            props.diagnostics.push(Severity::Warning, file, Some(&name), Some(key), "unknown key".to_owned());
         }
+    }
+    res
+}
 
-        for (key, value) in org_table.iter() {
-            final_table.insert(key.clone(), value.clone());
+fn get_effects(
+    prop: &str,
+    props: &Props,
+    effects: &InfoCollection<EffectInfo>,
+) -> Vec<Rc<EffectInfo>> {
+    let mut res = Vec::new();
+    if let Some(names) = get_array_string(prop, props) {
+        for name in names.iter() {
+            let Some(effect) = effects.get(name) else {
+                props.error(prop, format!("unknown effect \"{}\"", name));
+                continue;
+            };
+            res.push(effect.clone());
         }
+    }
+    res
+}
 
-        tables.insert(name.clone(), toml::Value::Table(final_table));
+/// Names of the tables a table extends, `extends` is either a name or an array of names
+fn parents(table: &Table) -> Result<Vec<&str>, String> {
+    match table.get("extends") {
+        None => Ok(Vec::new()),
+        Some(Value::String(parent)) => Ok(vec![parent.as_str()]),
+        Some(Value::Array(parents)) => parents
+            .iter()
+            .map(|x| x.as_str().ok_or_else(|| format!("expected a string, found {}", x.type_str())))
+            .collect(),
+        Some(v) => Err(format!("expected a string or an array of strings, found {}", v.type_str())),
     }
+}
 
-    tables
+/// Extends every table with the content of the tables named by its 'extends' key.
+///
+/// Parents are resolved recursively and applied in order, such that later parents override earlier
+/// ones and the keys of the table itself override all of them.
+fn extend_table(layered: &Layered, d: &Diagnostics) -> Extended {
+    let mut resolved = HashMap::new();
+    let mut res = Extended { table: Table::default(), origins: HashMap::new() };
+    for (name, value) in layered.table.iter() {
+        match resolve_table(name, layered, &mut resolved, &mut Vec::new(), d) {
+            Some((table, origins)) => {
+                res.table.insert(name.clone(), Value::Table(table));
+                res.origins.insert(name.clone(), origins);
+            }
+            None => {
+                res.table.insert(name.clone(), value.clone());
+            }
+        };
+    }
+    res
 }
 
-/// Load table from path and extend it using the `extend_table` function
-async fn load_and_extend_table(path: &str) -> Table {
-    let table = load_table(path).await;
-    extend_table(table)
+/// Table extended by its parents and the table each of its keys was defined in, `stack` holds the
+/// tables being resolved to detect cycles
+fn resolve_table(
+    name: &str,
+    layered: &Layered,
+    resolved: &mut HashMap<String, (Table, HashMap<String, String>)>,
+    stack: &mut Vec<String>,
+    d: &Diagnostics,
+) -> Option<(Table, HashMap<String, String>)> {
+    if let Some(table) = resolved.get(name) {
+        return Some(table.clone());
+    }
+    let tables = &layered.table;
+    let table = tables.get(name)?.as_table()?;
+    let file = layered.file(name);
+    if let Some(start) = stack.iter().position(|x| x == name) {
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(name.to_owned());
+        let message = format!("cycle {}", cycle.join(" -> "));
+        let last = stack.last().map(|x| x.as_str());
+        d.error(last.map(|x| layered.file(x)).unwrap_or(file), last, Some("extends"), message);
+        return None;
+    }
+
+    stack.push(name.to_owned());
+    let mut final_table = Table::default();
+    let mut origins = HashMap::new();
+    match parents(table) {
+        Ok(parents) => {
+            for parent in parents {
+                if !tables.contains_key(parent) {
+                    d.error(file, Some(name), Some("extends"), format!("unknown table \"{}\"", parent));
+                    continue;
+                }
+                if let Some((parent_table, parent_origins)) = resolve_table(parent, layered, resolved, stack, d) {
+                    final_table.extend(parent_table);
+                    origins.extend(parent_origins);
+                }
+            }
+        }
+        Err(message) => d.error(file, Some(name), Some("extends"), message),
+    }
+    stack.pop();
+
+    for (key, value) in table.iter() {
+        final_table.insert(key.clone(), value.clone());
+        origins.insert(key.clone(), name.to_owned());
+    }
+    resolved.insert(name.to_owned(), (final_table.clone(), origins.clone()));
+    Some((final_table, origins))
 }
 
-/// Load table from path
-async fn load_table(path: &str) -> Table {
-    let table = String::from_utf8(load_file(path).await.unwrap()).unwrap();
-    toml::from_str(&table).unwrap()
+/// Contents of files which have been read, by path
+type Files = HashMap<String, Result<Vec<u8>, String>>;
+
+/// Parses the contents of the file, none if it could not be read or parsed
+fn parse_table(path: &str, files: &Files, d: &Diagnostics) -> Option<Table> {
+    let bytes = match files.get(path) {
+        Some(Ok(bytes)) => bytes,
+        Some(Err(e)) => {
+            d.error(path, None, None, format!("could not read file: {}", e));
+            return None;
+        }
+        None => {
+            d.error(path, None, None, "file was not read".to_owned());
+            return None;
+        }
+    };
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        d.error(path, None, None, "file is not valid UTF-8".to_owned());
+        return None;
+    };
+    match toml::from_str(text) {
+        Ok(table) => Some(table),
+        Err(e) => {
+            let line = e.span().map(|x| text[..x.start].lines().count().max(1)).unwrap_or(1);
+            d.error(path, None, None, format!("line {}: {}", line, e.message().trim().replace('\n', ", ")));
+            None
+        }
+    }
 }
 
-async fn load_images(table: &Table) -> InfoCollection<ImageInfo> {
-    let mut map = HashMap::default();
-    for (name, value) in table.iter() {
-        let Some(path) = value.as_str() else { continue; };
-        let texture = load_texture(&("assets/".to_owned() + path))
-            .await
-            .expect("failed to load texture");
+/// Files of the packs which are read before the images: the manifests of the mods and the metadata
+/// files of every pack
+fn pack_files(mods: &[String]) -> Vec<String> {
+    let mut files: Vec<String> = mods.iter().map(|x| ContentPack::manifest_path(x)).collect();
+    let roots = std::iter::once(BASE_ROOT.to_owned()).chain(mods.iter().map(|x| format!("{}/{}", MODS_DIR, x)));
+    for root in roots {
+        for file in [IMAGES_FILE, EFFECTS_FILE, WEAPONS_FILE, ACTORS_FILE] {
+            files.push(format!("{}/{}", root, file));
+        }
+    }
+    files
+}
+
+/// Reads the manifests of the mods and merges the metadata files of the base game and the mods.
+///
+/// Mods whose manifest can not be read are skipped, their metadata files are optional.
+fn parse_packs(mods: &[String], files: &Files, d: &Diagnostics) -> Sources {
+    let mut packs = vec![ContentPack::base()];
+    for id in mods.iter() {
+        let path = ContentPack::manifest_path(id);
+        let Some(manifest) = parse_table(&path, files, d) else { continue; };
+        match ContentPack::from_manifest(id, &manifest) {
+            Ok(pack) => packs.push(pack),
+            Err(e) => d.error(&path, None, e.key.as_deref(), e.message),
+        }
+    }
+    for (i, pack) in packs.iter().enumerate() {
+        for dependency in pack.dependencies.iter() {
+            if packs[..i].iter().any(|x| &x.id == dependency) {
+                continue;
+            }
+            let message = match packs[i..].iter().any(|x| &x.id == dependency) {
+                true => format!("\"{}\" must be enabled before this mod", dependency),
+                false => format!("\"{}\" is not enabled", dependency),
+            };
+            d.error(&ContentPack::manifest_path(&pack.id), None, Some("dependencies"), message);
+        }
+    }
+
+    let mut sources = Sources {
+        packs: Vec::new(),
+        images: Layered::new(IMAGES_FILE),
+        effects: Layered::new(EFFECTS_FILE),
+        weapons: Layered::new(WEAPONS_FILE),
+        actors: Layered::new(ACTORS_FILE),
+    };
+    for pack in packs.iter() {
+        let layers = [&mut sources.images, &mut sources.effects, &mut sources.weapons, &mut sources.actors];
+        for layered in layers {
+            let path = pack.path(layered.name);
+            if !pack.is_base() && !matches!(files.get(&path), Some(Ok(_))) {
+                continue;
+            }
+            let Some(mut table) = parse_table(&path, files, d) else { continue; };
+            if layered.name == IMAGES_FILE {
+                // image paths are relative to the pack which defines them
+                for (_, value) in table.iter_mut() {
+                    let path = match value {
+                        Value::Table(sheet) => sheet.get_mut("path"),
+                        value => Some(value),
+                    };
+                    if let Some(Value::String(path)) = path {
+                        *path = pack.path(path);
+                    }
+                }
+            }
+            layered.merge(&path, table);
+        }
+    }
+    sources.packs = packs;
+    sources
+}
+
+/// Path of the file of an image, either the value itself or the `path` of a sprite sheet
+fn image_path(value: &Value) -> Option<&str> {
+    match value {
+        Value::Table(sheet) => sheet.get("path")?.as_str(),
+        value => value.as_str(),
+    }
+}
+
+/// Paths of the image files
+fn image_paths(images: &Layered) -> Vec<String> {
+    let mut paths: Vec<String> = images.table.values().filter_map(image_path).map(|x| x.to_owned()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Decodes an image file, the texture is only created if `gpu` is set
+fn load_texture(path: &str, files: &Files, gpu: bool) -> Result<(Option<Texture2D>, Rc<Image>), String> {
+    let bytes = match files.get(path) {
+        Some(Ok(bytes)) => bytes,
+        Some(Err(e)) => return Err(format!("could not read \"{}\": {}", path, e)),
+        None => return Err(format!("could not read \"{}\": not loaded", path)),
+    };
+    let image = Image::from_file_with_format(bytes, None).map_err(|e| format!("could not decode \"{}\": {}", path, e))?;
+    let texture = gpu.then(|| {
+        let texture = Texture2D::from_image(&image);
         texture.set_filter(macroquad::miniquad::FilterMode::Nearest);
+        texture
+    });
+    Ok((texture, Rc::new(image)))
+}
+
+/// Frames of a sprite sheet, either explicit `rects` or the cells of a `grid` of the given cell size
+fn sheet_frames(props: &Props, size: Vec2) -> Vec<Rect> {
+    let whole = Rect::new(0.0, 0.0, size.x, size.y);
+    let frames = match (get_rects("rects", props), get_tuple_f32("grid", props)) {
+        (Some(_), Some(_)) => {
+            props.error("grid", "expected either rects or grid".to_owned());
+            return vec![whole];
+        }
+        (Some(rects), None) => rects,
+        (None, Some((width, height))) if width < 1.0 || height < 1.0 => {
+            props.error("grid", "cells must be at least one pixel".to_owned());
+            return vec![whole];
+        }
+        (None, Some((width, height))) => {
+            let columns = (size.x / width).floor() as usize;
+            let rows = (size.y / height).floor() as usize;
+            let cell = |i: usize| Rect::new((i % columns) as f32 * width, (i / columns) as f32 * height, width, height);
+            (0..columns * rows).map(cell).collect()
+        }
+        (None, None) => vec![whole],
+    };
+    if frames.is_empty() {
+        props.error("grid", "sheet has no frames".to_owned());
+        return vec![whole];
+    }
+    for (i, frame) in frames.iter().enumerate() {
+        if frame.x < 0.0 || frame.y < 0.0 || frame.right() > size.x || frame.bottom() > size.y {
+            props.error("rects", format!("frame {} is outside of the {}x{} image", i, size.x, size.y));
+        }
+    }
+    frames
+}
+
+/// Loads the images from the contents of their files, textures are only created if `gpu` is set.
+///
+/// Every file is decoded once, such that the images of a sprite sheet or atlas share its texture.
+fn load_images(images: &Layered, files: &Files, gpu: bool, d: &Diagnostics) -> InfoCollection<ImageInfo> {
+    let tables = Tables::new(images, &images.table, d);
+    let mut textures = HashMap::new();
+    let mut map = HashMap::default();
+    for (name, value) in images.table.iter() {
+        let file = images.file(name);
+        let props = Props {
+            file,
+            table: name,
+            value,
+            diagnostics: d,
+            known: &tables.known,
+            origins: None,
+        };
+        let Some(path) = image_path(value) else {
+            match value.is_table() {
+                true => props.error("path", "expected the path of the sprite sheet".to_owned()),
+                false => d.error(file, None, Some(name), format!("expected a path or a table, found {}", value.type_str())),
+            }
+            continue;
+        };
+        props.get("path");
+        let texture = textures.entry(path).or_insert_with(|| load_texture(path, files, gpu));
+        let (texture, pixels) = match texture {
+            Ok(texture) => texture.clone(),
+            Err(e) => {
+                d.error(file, None, Some(name), e.clone());
+                continue;
+            }
+        };
+        let size = Vec2::new(pixels.width() as f32, pixels.height() as f32);
+        let frames = match value.is_table() {
+            true => sheet_frames(&props, size),
+            false => vec![Rect::new(0.0, 0.0, size.x, size.y)],
+        };
         map.insert(
             name.to_owned(),
             Rc::new(ImageInfo {
                 name: name.to_owned(),
                 path: path.to_owned(),
                 texture,
+                pixels,
+                frames,
             }),
         );
     }
+    tables.warn_unknown_keys();
     map
 }
 
-async fn load_weapons(
-    table: &Table,
+fn load_effects(tables: &Tables) -> InfoCollection<EffectInfo> {
+    let mut map = InfoCollection::default();
+    for (name, props) in tables.iter() {
+        let stacking = match get_str("stacking", &props) {
+            Some("stack") => Stacking::Stack,
+            Some("ignore") => Stacking::Ignore,
+            Some("refresh") | None => Stacking::Refresh,
+            Some(other) => {
+                props.error("stacking", format!("expected \"refresh\", \"stack\" or \"ignore\", found \"{}\"", other));
+                Stacking::Refresh
+            }
+        };
+        map.insert(
+            name.to_owned(),
+            Rc::new(EffectInfo {
+                name: name.to_owned(),
+                duration: get_f32("duration", &props).unwrap_or_default(),
+                damage_per_second: get_f32("damage_per_second", &props).unwrap_or_default(),
+                speed_multiplier: get_f32("speed_multiplier", &props).unwrap_or(1.0),
+                stun: get_bool("stun", &props).unwrap_or_default(),
+                stacking,
+                max_stacks: get_f32("max_stacks", &props).unwrap_or(1.0) as u32,
+                tint: get_vec4("tint", &props).unwrap_or(Vec4::ONE),
+            }),
+        );
+    }
+    map
+}
+
+fn load_weapons(
+    tables: &Tables,
     images: &InfoCollection<ImageInfo>,
+    effects: &InfoCollection<EffectInfo>,
 ) -> HashMap<String, Rc<WeaponInfo>> {
     let mut map = InfoCollection::default();
     map.insert("".to_string(), Rc::new(WeaponInfo::default()));
-    for (name, props) in table.iter() {
-        let damage = match get_array_f32("damage", props) {
+    for (name, props) in tables.iter() {
+        let damage = match get_array_f32("damage", &props) {
             Some(damage) => [
                 damage.first().copied().unwrap_or_default(),
                 damage.get(1).copied().unwrap_or_default(),
@@ -245,73 +1217,277 @@ async fn load_weapons(
             name.to_owned(),
             Rc::new(WeaponInfo {
                 name: name.to_owned(),
-                rate_of_fire: get_f32("rate_of_fire", props).unwrap_or_default(),
-                frames: get_frames("frames", props, images),
+                display_name: get_str("display_name", &props).unwrap_or(name).to_owned(),
+                rate_of_fire: get_f32("rate_of_fire", &props).unwrap_or_default(),
+                frames: get_frames("frames", &props, images),
+                firing_time: get_f32("firing_time", &props).unwrap_or(0.1),
+                muzzle_flash: get_frames("muzzle_flash", &props, images),
+                muzzle_flash_size: get_f32("muzzle_flash_size", &props).unwrap_or(1.0),
+                muzzle_light: get_vec4("muzzle_light", &props).unwrap_or(Vec4::ZERO),
+                muzzle_light_radius: get_f32("muzzle_light_radius", &props).unwrap_or(1.0),
+                shake: get_f32("shake", &props).unwrap_or_default(),
                 damage,
-                mount_offset: get_f32("mount_offset", props).unwrap_or_default(),
-                muzzle_offset: get_f32("muzzle_offset", props).unwrap_or_default(),
-                spread: get_f32("spread", props).unwrap_or_default(),
-                projectile: get_str("projectile", props).unwrap_or_default().to_string(),
-                range: get_f32("range", props).unwrap_or_default()
+                mount_offset: get_f32("mount_offset", &props).unwrap_or_default(),
+                muzzle_offset: get_f32("muzzle_offset", &props).unwrap_or_default(),
+                spread: get_f32("spread", &props).unwrap_or_default(),
+                projectile: get_str("projectile", &props).unwrap_or_default().to_string(),
+                range: get_f32("range", &props).unwrap_or_default(),
+                effects: get_effects("effects", &props, effects),
+                knockback: get_f32("knockback", &props).unwrap_or_default(),
+                melee: get_bool("melee", &props).unwrap_or_default(),
+                melee_reach: get_f32("melee_reach", &props).unwrap_or_default(),
+                melee_arc: get_f32("melee_arc", &props).unwrap_or_default(),
+                windup: get_f32("windup", &props).unwrap_or_default(),
+                recovery: get_f32("recovery", &props).unwrap_or_default(),
             }),
         );
     }
     map
 }
 
-async fn load_actors(
-    table: &Table,
+fn load_actors(
+    tables: &Tables,
     images: &InfoCollection<ImageInfo>,
     weapons: &InfoCollection<WeaponInfo>,
+    effects: &InfoCollection<EffectInfo>,
 ) -> InfoCollection<ActorInfo> {
     let mut map = InfoCollection::default();
 
-    for (name, props) in table.iter() {
+    for (name, props) in tables.iter() {
+        let weapon_name = get_str("weapon", &props).unwrap_or_default();
+        let weapon = match weapons.get(weapon_name) {
+            Some(weapon) => weapon.clone(),
+            None => {
+                props.error("weapon", format!("unknown weapon \"{}\"", weapon_name));
+                Rc::new(WeaponInfo::default())
+            }
+        };
         map.insert(
             name.to_owned(),
             Rc::new(ActorInfo {
                 name: name.to_owned(),
-                frames: get_frames("frames", props, images),
-                locomotion_frames: get_frames("locomotion_frames", props, images),
-                dead_frames: get_frames("dead_frames", props, images),
-                bot: get_bool("bot", props).unwrap_or_default(),
-                speed: get_f32("speed", props).unwrap_or_default(),
-                radius: get_f32("radius", props).unwrap_or_default(),
-                missile: get_bool("missile", props).unwrap_or_default(),
-                shootable: get_bool("shootable", props).unwrap_or_default(),
-                health: get_f32("health", props).unwrap_or_default(),
-                solid: get_bool("solid", props).unwrap_or_default(),
-                particle: get_bool("particle", props).unwrap_or_default(),
-                weapon: weapons
-                    .get(get_str("weapon", props).unwrap_or_default())
-                    .expect("could not find weapon")
-                    .clone(),
-                offset: get_vec2("offset", props).unwrap_or_default(),
-                rotate_to_face: get_bool("rotate_to_face", props).unwrap_or_default(),
-                missile_direct_damage: get_tuple_f32("missile_direct_damage", props)
+                clips: Clip::ALL.iter().map(|x| get_clip(*x, &props, images)).collect(),
+                bot: get_bool("bot", &props).unwrap_or_default(),
+                team: get_str("team", &props).unwrap_or_default().to_owned(),
+                speed: get_f32("speed", &props).unwrap_or_default(),
+                radius: get_f32("radius", &props).unwrap_or_default(),
+                missile: get_bool("missile", &props).unwrap_or_default(),
+                shootable: get_bool("shootable", &props).unwrap_or_default(),
+                health: get_f32("health", &props).unwrap_or_default(),
+                solid: get_bool("solid", &props).unwrap_or_default(),
+                particle: get_bool("particle", &props).unwrap_or_default(),
+                weapon,
+                offset: get_vec2("offset", &props).unwrap_or_default(),
+                rotate_to_face: get_bool("rotate_to_face", &props).unwrap_or_default(),
+                missile_direct_damage: get_tuple_f32("missile_direct_damage", &props)
                     .unwrap_or_default(),
-                missile_splash_damage: get_tuple_f32("missile_splash_damage", props)
+                missile_splash_damage: get_tuple_f32("missile_splash_damage", &props)
                     .unwrap_or_default(),
-                max_age: get_f32("max_age", props).unwrap_or_default(),
-                velocity: get_f32("velocity", props).unwrap_or_default()
+                max_age: get_f32("max_age", &props).unwrap_or_default(),
+                velocity: get_f32("velocity", &props).unwrap_or_default(),
+                effects: get_effects("effects", &props, effects),
+                knockback: get_f32("knockback", &props).unwrap_or_default(),
+                mass: get_f32("mass", &props).unwrap_or(1.0),
+                pierce_count: get_f32("pierce_count", &props).unwrap_or_default() as u32,
+                pierce_falloff: get_f32("pierce_falloff", &props).unwrap_or(1.0),
+                bounce_count: get_f32("bounce_count", &props).unwrap_or_default() as u32,
+                corpse_lifetime: get_f32("corpse_lifetime", &props).unwrap_or_default(),
+                corpse_fade: get_f32("corpse_fade", &props).unwrap_or_default(),
+                gib_threshold: get_f32("gib_threshold", &props).unwrap_or_default(),
+                gibs: get_str("gibs", &props).unwrap_or_default().to_string(),
+                gib_count: get_f32("gib_count", &props).unwrap_or_default() as u32,
             }),
         );
     }
     map
 }
 
+/// Reports problems which do not prevent loading but are likely mistakes
+fn lint(md: &Metadata, sources: &Sources, weapons: &Tables, actors_tables: &Tables, d: &Diagnostics) {
+    let mut referenced = HashSet::new();
+    for weapon in md.weapon_ids.iter().filter_map(|x| md.weapons.get(x)) {
+        referenced.extend(weapon.frames.iter().chain(weapon.muzzle_flash.iter()).map(|x| x.image.name.clone()));
+        if !weapon.projectile.is_empty() && !md.actors.contains_key(&weapon.projectile) {
+            let message = format!("projectile \"{}\" is not an actor", weapon.projectile);
+            let (file, table) = weapons.origin(&weapon.name, "projectile");
+            d.error(file, Some(table), Some("projectile"), message);
+        }
+    }
+
+    // tables which are extended by other tables are not used on their own
+    let actors = &sources.actors;
+    let extended: HashSet<&str> = actors
+        .table
+        .values()
+        .filter_map(|x| x.as_table())
+        .flat_map(|x| parents(x).unwrap_or_default())
+        .collect();
+    for actor in md.actor_ids.iter().filter_map(|x| md.actors.get(x)) {
+        let frames = actor.clips.iter().flat_map(|x| x.frames.iter());
+        referenced.extend(frames.map(|x| x.image.name.clone()));
+        let file = actors.file(&actor.name);
+        if !actor.gibs.is_empty() && !md.actors.contains_key(&actor.gibs) {
+            let (file, table) = actors_tables.origin(&actor.name, "gibs");
+            d.error(file, Some(table), Some("gibs"), format!("\"{}\" is not an actor", actor.gibs));
+        }
+        let raw = actors.table.get(&actor.name);
+        let is_empty = |x: Option<&Value>| x.and_then(|x| x.as_array()).map(|x| x.is_empty()).unwrap_or_default();
+        for clip in Clip::ALL {
+            if let Some(key) = clip.legacy_key().filter(|x| is_empty(raw.and_then(|raw| raw.get(x)))) {
+                d.push(Severity::Warning, file, Some(&actor.name), Some(key), "empty frame list".to_owned());
+            }
+            if is_empty(raw.and_then(|x| x.get(clip.key())).and_then(|x| x.get("frames"))) {
+                let table = format!("{}.{}", actor.name, clip.key());
+                d.push(Severity::Warning, file, Some(&table), Some("frames"), "empty frame list".to_owned());
+            }
+        }
+        if actor.clip(Clip::Idle).frames.is_empty() && !extended.contains(actor.name.as_str()) {
+            d.push(Severity::Warning, file, Some(&actor.name), None, "actor has no frames".to_owned());
+        }
+    }
+
+    for name in sources.images.table.keys() {
+        if !referenced.contains(name) {
+            let file = sources.images.file(name);
+            d.push(Severity::Warning, file, None, Some(name), "image is never referenced".to_owned());
+        }
+    }
+}
+
 impl Metadata {
-    pub async fn new() -> Self {
-        let images = load_table("assets/images.toml").await;
-        let images = load_images(&images).await;
-        let weapons = load_and_extend_table("assets/weapons.toml").await;
-        let weapons = load_weapons(&weapons, &images).await;
-        let actors = load_and_extend_table("assets/actors.toml").await;
-        let actors = load_actors(&actors, &images, &weapons).await;
-        Metadata {
+    /// Loads the metadata of the base game and the mods, which are layered in order.
+    ///
+    /// Every problem found is reported, loading fails if any of them is an error.
+    pub async fn new(mods: &[String]) -> Result<Self, MetadataError> {
+        let d = Diagnostics::default();
+        let mut files = Files::new();
+        for path in pack_files(mods) {
+            let bytes = load_file(&path).await.map_err(|e| e.to_string());
+            files.insert(path, bytes);
+        }
+        let sources = parse_packs(mods, &files, &d);
+        for path in image_paths(&sources.images) {
+            let bytes = load_file(&path).await.map_err(|e| e.to_string());
+            files.insert(path, bytes);
+        }
+        let md = Self::from_sources(sources, &files, true, &d);
+        md.with_diagnostics(d)
+    }
+
+    /// Loads the metadata like `new`, but reads the files synchronously from disk.
+    ///
+    /// Textures are only created if `gpu` is set, such that no window is needed otherwise.
+    pub fn from_disk(mods: &[String], gpu: bool) -> Result<Self, MetadataError> {
+        let d = Diagnostics::default();
+        let read = |path: String| {
+            let bytes = std::fs::read(&path).map_err(|e| e.to_string());
+            (path, bytes)
+        };
+        let mut files: Files = pack_files(mods).into_iter().map(read).collect();
+        let sources = parse_packs(mods, &files, &d);
+        files.extend(image_paths(&sources.images).into_iter().map(read));
+        let md = Self::from_sources(sources, &files, gpu, &d);
+        md.with_diagnostics(d)
+    }
+
+    /// Loads the metadata without a window, decoding images without creating textures.
+    ///
+    /// Returns every problem found.
+    pub fn check(mods: &[String]) -> Vec<Diagnostic> {
+        match Self::from_disk(mods, false) {
+            Ok(md) => md.warnings,
+            Err(e) => e.diagnostics,
+        }
+    }
+
+    /// Fails if any of the diagnostics is an error, otherwise keeps them as warnings
+    fn with_diagnostics(self, d: Diagnostics) -> Result<Self, MetadataError> {
+        let diagnostics = d.list.into_inner();
+        if diagnostics.iter().any(|x| x.severity == Severity::Error) {
+            return Err(MetadataError { diagnostics });
+        }
+        Ok(Metadata {
+            warnings: diagnostics,
+            ..self
+        })
+    }
+
+    /// Ids of the enabled mods, in order
+    pub fn mods(&self) -> Vec<String> {
+        self.packs.iter().filter(|x| !x.is_base()).map(|x| x.id.clone()).collect()
+    }
+
+    /// Files the metadata was loaded from, including files of the packs which do not exist (yet)
+    pub fn files(&self) -> Vec<String> {
+        let mut files = pack_files(&self.mods());
+        files.extend(self.images.values().map(|x| x.path.clone()));
+        files
+    }
+
+    /// Loads the metadata from the merged files of the packs
+    fn from_sources(sources: Sources, files: &Files, gpu: bool, d: &Diagnostics) -> Self {
+        let images = load_images(&sources.images, files, gpu, d);
+
+        let effects = extend_table(&sources.effects, d);
+        let effects_tables = Tables::extended(&sources.effects, &effects, d);
+        let effects = load_effects(&effects_tables);
+        effects_tables.warn_unknown_keys();
+
+        let weapons = extend_table(&sources.weapons, d);
+        let weapons_tables = Tables::extended(&sources.weapons, &weapons, d);
+        let weapons = load_weapons(&weapons_tables, &images, &effects);
+        weapons_tables.warn_unknown_keys();
+
+        let actors = extend_table(&sources.actors, d);
+        let actors_tables = Tables::extended(&sources.actors, &actors, d);
+        let actors = load_actors(&actors_tables, &images, &weapons, &effects);
+        actors_tables.warn_unknown_keys();
+
+        let md = Metadata {
             images,
+            effects,
+            weapon_ids: ids(&weapons),
+            actor_ids: ids(&actors),
             weapons,
             actors,
+            warnings: Vec::new(),
+            packs: sources.packs.clone(),
+        };
+        lint(&md, &sources, &weapons_tables, &actors_tables, d);
+        md
+    }
+
+    pub fn weapon_id(&self, name: &str) -> Option<u16> {
+        self.weapon_ids.binary_search_by(|x| x.as_str().cmp(name)).ok().map(|x| x as u16)
+    }
+
+    pub fn actor_id(&self, name: &str) -> Option<u16> {
+        self.actor_ids.binary_search_by(|x| x.as_str().cmp(name)).ok().map(|x| x as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inherited_keys_are_reported_against_their_table() {
+        let mut layered = Layered::new("actors.toml");
+        layered.merge("base/actors.toml", toml::from_str("[base]\nspeed = \"fast\"\nwalk = { fps = 0 }").unwrap());
+        layered.merge("mod/actors.toml", toml::from_str("[child]\nextends = \"base\"\nhealth = 10").unwrap());
+        let d = Diagnostics::default();
+        let extended = extend_table(&layered, &d);
+        let tables = Tables::extended(&layered, &extended, &d);
+        load_actors(&tables, &HashMap::new(), &HashMap::new(), &HashMap::new());
+
+        let list = d.list.borrow();
+        for (table, key) in [("base", "speed"), ("base.walk", "fps")] {
+            let reported: Vec<_> = list.iter().filter(|x| x.key.as_deref() == Some(key)).collect();
+            // once while loading the base itself and once while loading the child
+            assert_eq!(reported.len(), 2, "{:?}", reported);
+            for x in reported {
+                assert_eq!((x.file.as_str(), x.table.as_deref()), ("base/actors.toml", Some(table)));
+            }
         }
     }
 }
@@ -0,0 +1,584 @@
+//! Networked multiplayer over UDP.
+//!
+//! The host runs the simulation and is authoritative, clients send their `PlayerInput` and receive
+//! `StateSnapshot`s which they interpolate between, while predicting the movement of their own actor.
+//! Snapshots are sent as a `SnapshotDelta` against the newest snapshot acknowledged by the client.
+//! Messages larger than a datagram are split into fragments, a message is lost if any of its
+//! fragments is.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    rc::Rc,
+    time::{Instant, SystemTime},
+};
+
+use bincode::Options;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{DeltaError, Metadata, Peer, PlayerInput, Rng, SnapshotDelta, StateSnapshot};
+
+/// Seconds clients render behind the newest snapshot, such that there is something to interpolate towards
+pub const INTERPOLATION_DELAY: f64 = 0.1;
+
+/// Seconds between `Join` messages while a client waits for a `Welcome`
+const JOIN_INTERVAL: f64 = 0.5;
+
+/// Bytes of a message carried by each datagram, such that datagrams fit the MTU of most links
+const FRAGMENT_SIZE: usize = 1200;
+
+/// Largest datagram which can be received, a fragment and its header
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// Messages which need more fragments are not sent
+const MAX_FRAGMENTS: usize = 256;
+
+/// Messages of which only some fragments have arrived which are kept, older ones are given up
+const MAX_PARTIAL_MESSAGES: usize = 16;
+
+/// Seconds after which the host drops a client it has not heard from
+pub const CLIENT_TIMEOUT: f64 = 5.0;
+
+/// Number of snapshots kept as possible baselines for deltas
+const MAX_BASELINES: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+pub enum NetMessage {
+    /// sent by a client until it has been welcomed
+    Join,
+    /// sent by the host to a client which joined, with the index of its player
+    Welcome { player: usize },
+    /// sent by a client every tick, `tick` is the newest snapshot received by the client
+    Input { seq: u32, tick: u32, input: PlayerInput },
+    /// sent by the host to every client every tick, `ack` is the last input applied for the client
+    Snapshot { tick: u32, ack: u32, delta: SnapshotDelta },
+    /// sent by a peer every tick with its inputs of the most recent ticks, the newest being `frame`
+    PeerInput { frame: u32, inputs: Vec<PlayerInput> },
+    /// sent by a peer with the checksum of the state at the start of a tick
+    Checksum { frame: u32, checksum: u64 },
+}
+
+/// Datagram carrying a part of a serialized `NetMessage`
+#[derive(Serialize, Deserialize)]
+struct Fragment {
+    /// id of the message, counted per socket
+    message: u32,
+    index: u16,
+    count: u16,
+    bytes: Vec<u8>,
+}
+
+/// Current time in seconds, such that tests control the time seen by sockets
+pub type NetClock = Rc<dyn Fn() -> f64>;
+
+/// Seconds since the clock was created
+pub fn system_clock() -> NetClock {
+    let start = Instant::now();
+    Rc::new(move || start.elapsed().as_secs_f64())
+}
+
+/// Conditions simulated on outgoing packets, useful for testing over 127.0.0.1
+#[derive(Clone, Copy, Default)]
+pub struct NetConditions {
+    /// seconds each packet is delayed before being sent
+    pub latency: f64,
+    /// probability of a packet being dropped, between 0 and 1
+    pub packet_loss: f32,
+}
+
+/// Non-blocking UDP socket which sends packets according to the simulated `NetConditions`
+pub struct NetSocket {
+    socket: UdpSocket,
+    conditions: NetConditions,
+    clock: NetClock,
+    /// decides which packets are dropped
+    rng: Rng,
+    outgoing: VecDeque<(f64, SocketAddr, Vec<u8>)>,
+    /// id of the next message sent
+    message: u32,
+    /// fragments of messages being received, by sender and message id
+    partial: HashMap<(SocketAddr, u32), Vec<Option<Vec<u8>>>>,
+}
+
+/// Client connected to the host
+pub struct RemoteClient {
+    pub addr: SocketAddr,
+    pub player: usize,
+    /// sequence number of the newest input received
+    pub seq: u32,
+    /// newest input received, with the one-shot fields of earlier inputs which have not been taken
+    pub input: PlayerInput,
+    /// newest snapshot received by the client
+    pub tick: u32,
+    /// snapshots sent to the client, as reconstructed by the client
+    pub baselines: VecDeque<(u32, StateSnapshot)>,
+    /// time a message of the client was last received
+    pub last_heard: f64,
+}
+
+pub struct Server {
+    pub socket: NetSocket,
+    pub clients: Vec<RemoteClient>,
+    /// number of snapshots broadcast
+    pub tick: u32,
+    /// seconds played when each of the recent snapshots was broadcast, by tick
+    times: VecDeque<(u32, f32)>,
+}
+
+/// Input sent to the host which has not yet been acknowledged
+pub struct PendingInput {
+    pub seq: u32,
+    pub locomotion_dir: Vec2,
+    pub dt: f32,
+}
+
+pub struct Client {
+    pub socket: NetSocket,
+    pub server: SocketAddr,
+    /// index of the player controlled by this client, once welcomed
+    pub player: Option<usize>,
+    pub seq: u32,
+    pub pending: Vec<PendingInput>,
+    /// snapshots received together with the local time they were received and their tick
+    pub snapshots: VecDeque<(f64, u32, StateSnapshot)>,
+    /// snapshots received which the host can use as baselines
+    pub baselines: VecDeque<(u32, StateSnapshot)>,
+    last_join: f64,
+}
+
+pub enum Net {
+    Server(Server),
+    Client(Client),
+    Peer(Peer),
+}
+
+impl NetSocket {
+    /// Binds a socket which uses the system clock and drops packets at random
+    pub fn bind(addr: &str, conditions: NetConditions) -> std::io::Result<Self> {
+        let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or_default();
+        Self::bind_with(addr, conditions, system_clock(), Rng::new(seed))
+    }
+
+    /// Binds a socket which reads the time from `clock` and decides which packets are dropped with `rng`
+    pub fn bind_with(addr: &str, conditions: NetConditions, clock: NetClock, rng: Rng) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            conditions,
+            clock,
+            rng,
+            outgoing: VecDeque::new(),
+            message: 0,
+            partial: HashMap::new(),
+        })
+    }
+
+    pub fn now(&self) -> f64 {
+        (self.clock)()
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Queues the message as fragments, which are sent when the simulated latency has passed
+    pub fn send(&mut self, addr: SocketAddr, msg: &NetMessage) {
+        let bytes = bincode::DefaultOptions::new().serialize(msg).unwrap();
+        let count = bytes.len().div_ceil(FRAGMENT_SIZE).max(1);
+        if count > MAX_FRAGMENTS {
+            eprintln!("not sending a message of {} bytes to {}, the limit is {} bytes", bytes.len(), addr, MAX_FRAGMENTS * FRAGMENT_SIZE);
+            return;
+        }
+        let message = self.message;
+        self.message = self.message.wrapping_add(1);
+        let time = self.now() + self.conditions.latency;
+        for (index, chunk) in bytes.chunks(FRAGMENT_SIZE).enumerate() {
+            if self.rng.f32_0_1() < self.conditions.packet_loss {
+                continue;
+            }
+            let fragment = Fragment { message, index: index as u16, count: count as u16, bytes: chunk.to_vec() };
+            let datagram = bincode::DefaultOptions::new().serialize(&fragment).unwrap();
+            self.outgoing.push_back((time, addr, datagram));
+        }
+        self.flush();
+    }
+
+    /// Sends the queued datagrams whose latency has passed
+    pub fn flush(&mut self) {
+        let now = self.now();
+        while let Some((time, addr, bytes)) = self.outgoing.front() {
+            if *time > now {
+                break;
+            }
+            match self.socket.send_to(bytes, addr) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => eprintln!("failed to send to {}: {}", addr, e),
+                Ok(_) => {}
+            }
+            self.outgoing.pop_front();
+        }
+    }
+
+    /// Receives every message which has arrived completely, ignoring malformed packets
+    pub fn recv(&mut self) -> Vec<(SocketAddr, NetMessage)> {
+        self.flush();
+        let mut messages = Vec::new();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, addr)) => {
+                    let Ok(fragment) = bincode::DefaultOptions::new().deserialize(&buf[..size]) else {
+                        continue;
+                    };
+                    let Some(bytes) = self.reassemble(addr, fragment) else {
+                        continue;
+                    };
+                    if let Ok(msg) = bincode::DefaultOptions::new().deserialize(&bytes) {
+                        messages.push((addr, msg));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => continue,
+            }
+        }
+        messages
+    }
+
+    /// Keeps the fragment, returns the bytes of its message once every fragment has arrived
+    fn reassemble(&mut self, addr: SocketAddr, fragment: Fragment) -> Option<Vec<u8>> {
+        let count = fragment.count as usize;
+        if count == 0 || count > MAX_FRAGMENTS || fragment.index as usize >= count {
+            return None;
+        }
+        if count == 1 {
+            return Some(fragment.bytes);
+        }
+        let key = (addr, fragment.message);
+        let parts = self.partial.entry(key).or_insert_with(|| vec![None; count]);
+        if parts.len() != count {
+            return None;
+        }
+        parts[fragment.index as usize] = Some(fragment.bytes);
+        if parts.iter().all(|x| x.is_some()) {
+            let parts = self.partial.remove(&key)?;
+            return Some(parts.into_iter().flatten().flatten().collect());
+        }
+        if self.partial.len() > MAX_PARTIAL_MESSAGES {
+            let oldest = *self.partial.keys().min_by_key(|x| x.1)?;
+            self.partial.remove(&oldest);
+        }
+        None
+    }
+}
+
+impl RemoteClient {
+    /// Keeps the input if it is newer than the input received so far. A weapon switch or restart
+    /// of the replaced input is kept until it is taken.
+    pub fn receive_input(&mut self, seq: u32, input: PlayerInput) {
+        if seq <= self.seq {
+            return;
+        }
+        self.seq = seq;
+        self.input = PlayerInput {
+            weapon: input.weapon.or(self.input.weapon),
+            restart: input.restart || self.input.restart,
+            ..input
+        };
+    }
+}
+
+impl Server {
+    pub fn new(addr: &str, conditions: NetConditions) -> std::io::Result<Self> {
+        Ok(Self::from_socket(NetSocket::bind(addr, conditions)?))
+    }
+
+    pub fn from_socket(socket: NetSocket) -> Self {
+        Self {
+            socket,
+            clients: Vec::new(),
+            tick: 0,
+            times: VecDeque::new(),
+        }
+    }
+
+    pub fn client(&self, player: usize) -> Option<&RemoteClient> {
+        self.clients.iter().find(|x| x.player == player)
+    }
+
+    /// Input of the client controlling the player, its weapon switch and restart are only taken once
+    pub fn take_input(&mut self, player: usize) -> PlayerInput {
+        let Some(client) = self.clients.iter_mut().find(|x| x.player == player) else {
+            return PlayerInput::default();
+        };
+        let input = client.input.clone();
+        client.input = input.predicted();
+        input
+    }
+
+    /// Removes the clients which have not been heard from for `CLIENT_TIMEOUT`
+    pub fn drop_quiet_clients(&mut self) -> Vec<RemoteClient> {
+        let now = self.socket.now();
+        let (quiet, clients) = self.clients.drain(..).partition(|x| now - x.last_heard > CLIENT_TIMEOUT);
+        self.clients = clients;
+        quiet
+    }
+
+    /// Sends the snapshot taken after `playtime` seconds of play to every client, encoded against
+    /// the newest snapshot each client has received.
+    ///
+    /// Fails without sending anything if the snapshot cannot be encoded.
+    pub fn broadcast(&mut self, snapshot: &StateSnapshot, playtime: f32, md: &Metadata) -> Result<(), DeltaError> {
+        self.tick += 1;
+        self.times.push_back((self.tick, playtime));
+        if self.times.len() > MAX_BASELINES + 1 {
+            self.times.pop_front();
+        }
+        let mut messages = Vec::new();
+        for client in self.clients.iter_mut() {
+            client.baselines.retain(|x| x.0 >= client.tick);
+            let baseline = client.baselines.iter().find(|x| x.0 == client.tick);
+            let time = baseline.and_then(|x| self.times.iter().find(|t| t.0 == x.0));
+            let elapsed = time.map(|x| playtime - x.1).unwrap_or_default();
+            let delta = SnapshotDelta::encode(baseline.map(|x| (x.0, &x.1)), snapshot, elapsed, md)?;
+            let Some(reconstructed) = delta.apply(baseline.map(|x| &x.1), md) else {
+                continue;
+            };
+            client.baselines.push_back((self.tick, reconstructed));
+            if client.baselines.len() > MAX_BASELINES {
+                client.baselines.pop_front();
+            }
+            messages.push((client.addr, NetMessage::Snapshot { tick: self.tick, ack: client.seq, delta }));
+        }
+        for (addr, msg) in messages.iter() {
+            self.socket.send(*addr, msg);
+        }
+        Ok(())
+    }
+}
+
+impl Client {
+    pub fn new(server: &str, conditions: NetConditions) -> std::io::Result<Self> {
+        let server: SocketAddr = server
+            .parse()
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "invalid server address"))?;
+        let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        Ok(Self::from_socket(server, NetSocket::bind(local, conditions)?))
+    }
+
+    pub fn from_socket(server: SocketAddr, socket: NetSocket) -> Self {
+        Self {
+            socket,
+            server,
+            player: None,
+            seq: 0,
+            pending: Vec::new(),
+            snapshots: VecDeque::new(),
+            baselines: VecDeque::new(),
+            last_join: f64::MIN,
+        }
+    }
+
+    /// Sends `Join` until a `Welcome` has been received
+    pub fn join(&mut self) {
+        let now = self.socket.now();
+        if self.player.is_none() && now - self.last_join > JOIN_INTERVAL {
+            self.last_join = now;
+            self.socket.send(self.server, &NetMessage::Join);
+        }
+    }
+
+    /// Sends the input to the host and remembers it for prediction until acknowledged
+    pub fn send_input(&mut self, input: PlayerInput, dt: f32) {
+        self.seq += 1;
+        self.pending.push(PendingInput {
+            seq: self.seq,
+            locomotion_dir: input.locomotion_dir,
+            dt,
+        });
+        let tick = self.baselines.back().map(|x| x.0).unwrap_or_default();
+        let msg = NetMessage::Input { seq: self.seq, tick, input };
+        self.socket.send(self.server, &msg);
+    }
+
+    /// Decodes the snapshot, keeps it for interpolation and forgets the acknowledged inputs.
+    /// Snapshots arriving out of order or whose baseline is unknown are ignored.
+    pub fn receive_snapshot(&mut self, tick: u32, ack: u32, delta: SnapshotDelta, md: &Metadata) {
+        if let Some((newest, _)) = self.baselines.back() {
+            if *newest >= tick {
+                return;
+            }
+        }
+        let baseline = match delta.baseline {
+            Some(baseline) => match self.baselines.iter().find(|x| x.0 == baseline) {
+                Some(x) => Some(&x.1),
+                None => return,
+            },
+            None => None,
+        };
+        let Some(snapshot) = delta.apply(baseline, md) else {
+            return;
+        };
+        self.baselines.push_back((tick, snapshot.clone()));
+        if self.baselines.len() > MAX_BASELINES {
+            self.baselines.pop_front();
+        }
+        self.pending.retain(|x| x.seq > ack);
+        let now = self.socket.now();
+        self.snapshots.push_back((now, tick, snapshot));
+    }
+
+    /// Snapshot interpolated at the render time, which is `INTERPOLATION_DELAY` behind now.
+    /// Snapshots older than needed are discarded.
+    pub fn interpolated_snapshot(&mut self) -> Option<StateSnapshot> {
+        let render_time = self.socket.now() - INTERPOLATION_DELAY;
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= render_time {
+            self.snapshots.pop_front();
+        }
+        let (t0, _, s0) = self.snapshots.front()?;
+        let Some((t1, _, s1)) = self.snapshots.get(1) else {
+            return Some(s0.clone());
+        };
+        // snapshots received during the same frame have the same time
+        let alpha = match t1 > t0 {
+            true => ((render_time - t0) / (t1 - t0)).clamp(0.0, 1.0) as f32,
+            false => 1.0,
+        };
+        Some(interpolate(s0, s1, alpha))
+    }
+
+    /// Predicted displacement of the own actor from the inputs not yet acknowledged by the host
+    pub fn predicted_offset(&self, speed: f32) -> Vec2 {
+        self.pending
+            .iter()
+            .map(|x| x.locomotion_dir * speed * x.dt)
+            .fold(Vec2::ZERO, |a, b| a + b)
+    }
+}
+
+/// Lerps the position of actors found in both snapshots, everything else is taken from `to`
+fn interpolate(from: &StateSnapshot, to: &StateSnapshot, alpha: f32) -> StateSnapshot {
+    let prev: HashMap<_, _> = from.actors.iter().map(|x| (x.handle, x.state.pos)).collect();
+    let mut res = to.clone();
+    for actor in res.actors.iter_mut() {
+        let Some(prev) = prev.get(&actor.handle) else {
+            continue;
+        };
+        actor.state.pos = prev.lerp(actor.state.pos, alpha);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use super::*;
+    use crate::{State, StateSnapshot};
+
+    /// Clock which only advances when the returned cell is set
+    fn manual_clock() -> (Rc<Cell<f64>>, NetClock) {
+        let time = Rc::new(Cell::new(0.0));
+        let clock = time.clone();
+        (time, Rc::new(move || clock.get()))
+    }
+
+    fn socket(clock: NetClock) -> NetSocket {
+        NetSocket::bind_with("127.0.0.1:0", NetConditions::default(), clock, Rng::default()).unwrap()
+    }
+
+    /// Receives until a message has arrived or a second has passed
+    fn recv_one(socket: &mut NetSocket) -> Option<NetMessage> {
+        for _ in 0..1000 {
+            if let Some((_, msg)) = socket.recv().pop() {
+                return Some(msg);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn one_shot_inputs_are_kept_until_taken() {
+        let (_, clock) = manual_clock();
+        let mut server = Server::from_socket(socket(clock));
+        server.clients.push(RemoteClient {
+            addr: server.socket.local_addr().unwrap(),
+            player: 0,
+            seq: 0,
+            input: PlayerInput::default(),
+            tick: 0,
+            baselines: VecDeque::new(),
+            last_heard: 0.0,
+        });
+        let client = &mut server.clients[0];
+        client.receive_input(1, PlayerInput { weapon: Some(2), ..Default::default() });
+        client.receive_input(2, PlayerInput { fire: true, ..Default::default() });
+        // inputs arriving out of order are dropped
+        client.receive_input(1, PlayerInput { restart: true, ..Default::default() });
+
+        let input = server.take_input(0);
+        assert_eq!((input.weapon, input.fire, input.restart), (Some(2), true, false));
+        let input = server.take_input(0);
+        assert_eq!((input.weapon, input.fire), (None, true));
+    }
+
+    #[test]
+    fn large_messages_are_fragmented() {
+        let (_, clock) = manual_clock();
+        let (mut a, mut b) = (socket(clock.clone()), socket(clock));
+        let inputs = vec![PlayerInput { fire: true, ..Default::default() }; 2000];
+        a.send(b.local_addr().unwrap(), &NetMessage::PeerInput { frame: 7, inputs });
+        let Some(NetMessage::PeerInput { frame, inputs }) = recv_one(&mut b) else {
+            panic!("message not received");
+        };
+        assert_eq!(frame, 7);
+        assert_eq!(inputs.len(), 2000);
+        assert!(inputs.iter().all(|x| x.fire));
+    }
+
+    #[test]
+    fn oversized_messages_are_not_sent() {
+        let (_, clock) = manual_clock();
+        let (mut a, mut b) = (socket(clock.clone()), socket(clock));
+        let inputs = vec![PlayerInput::default(); MAX_FRAGMENTS * FRAGMENT_SIZE];
+        a.send(b.local_addr().unwrap(), &NetMessage::PeerInput { frame: 0, inputs });
+        a.send(b.local_addr().unwrap(), &NetMessage::Join);
+        assert!(matches!(recv_one(&mut b), Some(NetMessage::Join)));
+    }
+
+    #[test]
+    fn latency_is_simulated_with_the_clock() {
+        let (time, clock) = manual_clock();
+        let conditions = NetConditions { latency: 0.5, packet_loss: 0.0 };
+        let mut a = NetSocket::bind_with("127.0.0.1:0", conditions, clock.clone(), Rng::default()).unwrap();
+        let mut b = socket(clock);
+        a.send(b.local_addr().unwrap(), &NetMessage::Join);
+        a.flush();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(b.recv().is_empty());
+        time.set(0.5);
+        a.flush();
+        assert!(matches!(recv_one(&mut b), Some(NetMessage::Join)));
+    }
+
+    #[test]
+    fn interpolation_of_snapshots_received_together() {
+        let (time, clock) = manual_clock();
+        // the render time equals the time both snapshots were received
+        time.set(INTERPOLATION_DELAY);
+        let server = socket(clock.clone()).local_addr().unwrap();
+        let mut client = Client::from_socket(server, socket(clock));
+        let metadata = Metadata::from_disk(&[], false).unwrap();
+        let mut state = State::default();
+        let actor = state.spawn_actor(metadata.actors.get("guy").unwrap().clone());
+        actor.pos = Vec2::new(1.0, 2.0);
+        let snapshot = StateSnapshot::create_snapshot(&state, &metadata);
+        client.snapshots.push_back((0.0, 0, snapshot.clone()));
+        client.snapshots.push_back((0.0, 1, snapshot));
+        let snapshot = client.interpolated_snapshot().unwrap();
+        assert_eq!(snapshot.actors[0].state.pos, Vec2::new(1.0, 2.0));
+    }
+}
@@ -0,0 +1,530 @@
+//! Renderer abstraction.
+//!
+//! Drawing systems push `DrawCommand`s to the `DrawList` of the `Context` instead of drawing
+//! directly. After the systems have run, a `Renderer` backend executes the commands: macroquad in
+//! the game, a recording backend in tests and a software rasteriser which renders to an image
+//! without a GPU, such as for screenshots.
+
+use macroquad::prelude::*;
+
+use crate::ImageIndex;
+
+/// Transform from world coordinates to the screen, the same as a `Camera2D` without rotation or offset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct View {
+    pub target: Vec2,
+    pub zoom: Vec2,
+}
+
+/// How text is positioned relative to its position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Debug)]
+pub enum DrawCommand {
+    /// following commands are in world coordinates of the view
+    Camera(View),
+    /// following commands are in screen pixels
+    Screen,
+    Sprite {
+        frame: ImageIndex,
+        dest: Rect,
+        color: Color,
+        /// radians around the center of `dest`
+        rotation: f32,
+        flip_x: bool,
+        flip_y: bool,
+    },
+    Rect {
+        rect: Rect,
+        color: Color,
+    },
+    /// outline drawn inside of the rect
+    RectLines {
+        rect: Rect,
+        thickness: f32,
+        color: Color,
+    },
+    Line {
+        from: Vec2,
+        to: Vec2,
+        thickness: f32,
+        color: Color,
+    },
+    Circle {
+        center: Vec2,
+        radius: f32,
+        color: Color,
+    },
+    Triangle {
+        points: [Vec2; 3],
+        color: Color,
+    },
+    /// `pos` is on the baseline of the text
+    Text {
+        text: String,
+        pos: Vec2,
+        font_size: f32,
+        color: Color,
+        align: Align,
+    },
+}
+
+/// Draw commands of a frame, in the order they are drawn
+#[derive(Default)]
+pub struct DrawList {
+    pub commands: Vec<DrawCommand>,
+    /// size of the screen in pixels
+    pub screen: Vec2,
+}
+
+impl DrawList {
+    /// Clears the commands of the previous frame
+    pub fn begin(&mut self, screen: Vec2) {
+        self.commands.clear();
+        self.screen = screen;
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera2D) {
+        self.commands.push(DrawCommand::Camera(View {
+            target: camera.target,
+            zoom: camera.zoom,
+        }));
+    }
+
+    pub fn set_screen(&mut self) {
+        self.commands.push(DrawCommand::Screen);
+    }
+
+    pub fn sprite(&mut self, frame: &ImageIndex, dest: Rect, color: Color, rotation: f32, flip_x: bool, flip_y: bool) {
+        self.commands.push(DrawCommand::Sprite {
+            frame: frame.clone(),
+            dest,
+            color,
+            rotation,
+            flip_x,
+            flip_y,
+        });
+    }
+
+    pub fn rect(&mut self, x: f32, y: f32, w: f32, h: f32, color: Color) {
+        self.commands.push(DrawCommand::Rect {
+            rect: Rect::new(x, y, w, h),
+            color,
+        });
+    }
+
+    pub fn rect_lines(&mut self, x: f32, y: f32, w: f32, h: f32, thickness: f32, color: Color) {
+        self.commands.push(DrawCommand::RectLines {
+            rect: Rect::new(x, y, w, h),
+            thickness,
+            color,
+        });
+    }
+
+    pub fn line(&mut self, from: Vec2, to: Vec2, thickness: f32, color: Color) {
+        self.commands.push(DrawCommand::Line { from, to, thickness, color });
+    }
+
+    pub fn circle(&mut self, center: Vec2, radius: f32, color: Color) {
+        self.commands.push(DrawCommand::Circle { center, radius, color });
+    }
+
+    pub fn triangle(&mut self, a: Vec2, b: Vec2, c: Vec2, color: Color) {
+        self.commands.push(DrawCommand::Triangle { points: [a, b, c], color });
+    }
+
+    pub fn text(&mut self, text: &str, pos: Vec2, font_size: f32, color: Color, align: Align) {
+        self.commands.push(DrawCommand::Text {
+            text: text.to_owned(),
+            pos,
+            font_size,
+            color,
+            align,
+        });
+    }
+}
+
+/// Screen position of a point in world coordinates
+pub fn world_to_screen(view: &View, screen: Vec2, point: Vec2) -> Vec2 {
+    let ndc = (point - view.target) * view.zoom;
+    Vec2::new((ndc.x / 2.0 + 0.5) * screen.x, (ndc.y / 2.0 + 0.5) * screen.y)
+}
+
+/// Corners of a sprite after flipping and rotating it around its center, like macroquad does
+fn sprite_corners(dest: Rect, rotation: f32, flip_x: bool, flip_y: bool) -> [Vec2; 4] {
+    let (mut x, mut y, mut w, mut h) = (dest.x, dest.y, dest.w, dest.h);
+    if flip_x {
+        x += w;
+        w = -w;
+    }
+    if flip_y {
+        y += h;
+        h = -h;
+    }
+    let pivot = Vec2::new(x + w / 2.0, y + h / 2.0);
+    let rotate = Vec2::from_angle(rotation);
+    [Vec2::new(x, y), Vec2::new(x + w, y), Vec2::new(x + w, y + h), Vec2::new(x, y + h)].map(|p| pivot + rotate.rotate(p - pivot))
+}
+
+pub trait Renderer {
+    /// Size of the screen in pixels
+    fn screen_size(&self) -> Vec2;
+
+    /// Executes the commands of a frame
+    fn render(&mut self, commands: &[DrawCommand]);
+}
+
+/// Draws with macroquad, needs a window
+pub struct MacroquadRenderer;
+
+impl Renderer for MacroquadRenderer {
+    fn screen_size(&self) -> Vec2 {
+        Vec2::new(screen_width(), screen_height())
+    }
+
+    fn render(&mut self, commands: &[DrawCommand]) {
+        for command in commands.iter() {
+            match command {
+                DrawCommand::Camera(view) => set_camera(&Camera2D {
+                    target: view.target,
+                    zoom: view.zoom,
+                    ..Default::default()
+                }),
+                DrawCommand::Screen => set_default_camera(),
+                DrawCommand::Sprite { frame, dest, color, rotation, flip_x, flip_y } => {
+                    // images loaded without a GPU have no texture
+                    let Some(texture) = &frame.image.texture else { continue };
+                    draw_texture_ex(texture, dest.x, dest.y, *color, DrawTextureParams {
+                        dest_size: Some(dest.size()),
+                        source: Some(frame.source()),
+                        rotation: *rotation,
+                        flip_x: *flip_x,
+                        flip_y: *flip_y,
+                        ..Default::default()
+                    });
+                }
+                DrawCommand::Rect { rect, color } => draw_rectangle(rect.x, rect.y, rect.w, rect.h, *color),
+                DrawCommand::RectLines { rect, thickness, color } => {
+                    draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, *thickness, *color)
+                }
+                DrawCommand::Line { from, to, thickness, color } => draw_line(from.x, from.y, to.x, to.y, *thickness, *color),
+                DrawCommand::Circle { center, radius, color } => draw_circle(center.x, center.y, *radius, *color),
+                DrawCommand::Triangle { points, color } => draw_triangle(points[0], points[1], points[2], *color),
+                DrawCommand::Text { text, pos, font_size, color, align } => {
+                    let x = match align {
+                        Align::Left => pos.x,
+                        Align::Center => pos.x - measure_text(text, None, *font_size as u16, 1.0).width / 2.0,
+                        Align::Right => pos.x - measure_text(text, None, *font_size as u16, 1.0).width,
+                    };
+                    draw_text(text, x, pos.y, *font_size, *color);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the commands of every rendered frame, such that tests can assert against them
+#[derive(Default)]
+pub struct RecordingRenderer {
+    pub screen: Vec2,
+    pub frames: Vec<Vec<DrawCommand>>,
+}
+
+impl RecordingRenderer {
+    pub fn new(screen: Vec2) -> Self {
+        Self {
+            screen,
+            frames: Vec::new(),
+        }
+    }
+}
+
+impl Renderer for RecordingRenderer {
+    fn screen_size(&self) -> Vec2 {
+        self.screen
+    }
+
+    fn render(&mut self, commands: &[DrawCommand]) {
+        self.frames.push(commands.to_vec());
+    }
+}
+
+/// Rasterises the commands on the CPU into an image, such that frames can be rendered without a GPU.
+///
+/// Sprites are sampled from the pixels kept by `ImageInfo`, text uses a small built-in bitmap font.
+pub struct SoftwareRenderer {
+    pub width: usize,
+    pub height: usize,
+    /// pixels row by row from the top
+    pub pixels: Vec<Color>,
+    /// view of the commands being executed, none for screen coordinates
+    view: Option<View>,
+}
+
+impl SoftwareRenderer {
+    /// Renderer with a black image of the size
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![BLACK; width * height],
+            view: None,
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        self.pixels.fill(color);
+    }
+
+    /// Pixels as an opaque image
+    pub fn to_image(&self) -> Image {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in self.pixels.iter() {
+            let [r, g, b, _]: [u8; 4] = (*pixel).into();
+            bytes.extend([r, g, b, 255]);
+        }
+        Image {
+            bytes,
+            width: self.width as u16,
+            height: self.height as u16,
+        }
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        let image = self.to_image();
+        image::save_buffer(path, &image.bytes, self.width as u32, self.height as u32, image::ColorType::Rgba8)
+            .map_err(|e| format!("could not write {}: {}", path, e))
+    }
+
+    fn to_screen(&self, point: Vec2) -> Vec2 {
+        match &self.view {
+            Some(view) => world_to_screen(view, self.screen_size(), point),
+            None => point,
+        }
+    }
+
+    /// Blends the color over the pixel
+    fn blend(&mut self, x: usize, y: usize, color: Color) {
+        let pixel = &mut self.pixels[y * self.width + x];
+        let a = color.a.clamp(0.0, 1.0);
+        pixel.r = color.r * a + pixel.r * (1.0 - a);
+        pixel.g = color.g * a + pixel.g * (1.0 - a);
+        pixel.b = color.b * a + pixel.b * (1.0 - a);
+    }
+
+    /// Pixels whose centers are within the bounding box of the points
+    fn pixels_within(&self, points: &[Vec2]) -> impl Iterator<Item = (usize, usize, Vec2)> {
+        let min = points.iter().fold(Vec2::splat(f32::MAX), |a, b| a.min(*b));
+        let max = points.iter().fold(Vec2::splat(f32::MIN), |a, b| a.max(*b));
+        let x0 = (min.x - 0.5).ceil().max(0.0) as usize;
+        let y0 = (min.y - 0.5).ceil().max(0.0) as usize;
+        let x1 = ((max.x - 0.5).floor() + 1.0).clamp(0.0, self.width as f32) as usize;
+        let y1 = ((max.y - 0.5).floor() + 1.0).clamp(0.0, self.height as f32) as usize;
+        (y0..y1).flat_map(move |y| (x0..x1).map(move |x| (x, y, Vec2::new(x as f32 + 0.5, y as f32 + 0.5))))
+    }
+
+    /// Fills the parallelogram spanned by the first, second and fourth corner (in screen pixels),
+    /// `shade` returns the color at the coordinates within the quad, both from 0 to 1
+    fn fill_quad(&mut self, corners: [Vec2; 4], shade: impl Fn(f32, f32) -> Option<Color>) {
+        let u = corners[1] - corners[0];
+        let v = corners[3] - corners[0];
+        let det = u.perp_dot(v);
+        if det.abs() < f32::EPSILON {
+            return;
+        }
+        let pixels: Vec<_> = self.pixels_within(&corners).collect();
+        for (x, y, p) in pixels {
+            let d = p - corners[0];
+            let a = d.perp_dot(v) / det;
+            let b = u.perp_dot(d) / det;
+            if !(0.0..1.0).contains(&a) || !(0.0..1.0).contains(&b) {
+                continue;
+            }
+            if let Some(color) = shade(a, b) {
+                self.blend(x, y, color);
+            }
+        }
+    }
+
+    /// Fills a rect given in the coordinates of the current view
+    fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let corners = [rect.point(), Vec2::new(rect.right(), rect.y), Vec2::new(rect.right(), rect.bottom()), Vec2::new(rect.x, rect.bottom())];
+        self.fill_quad(corners.map(|x| self.to_screen(x)), |_, _| Some(color));
+    }
+
+    fn fill_triangle(&mut self, points: [Vec2; 3], color: Color) {
+        let [a, b, c] = points;
+        let area = (b - a).perp_dot(c - a);
+        if area.abs() < f32::EPSILON {
+            return;
+        }
+        let pixels: Vec<_> = self.pixels_within(&points).collect();
+        for (x, y, p) in pixels {
+            let w0 = (b - p).perp_dot(c - p) / area;
+            let w1 = (c - p).perp_dot(a - p) / area;
+            let w2 = 1.0 - w0 - w1;
+            if w0 >= 0.0 && w1 >= 0.0 && w2 >= 0.0 {
+                self.blend(x, y, color);
+            }
+        }
+    }
+
+    fn draw_sprite(&mut self, frame: &ImageIndex, corners: [Vec2; 4], color: Color) {
+        let image = frame.image.pixels.clone();
+        let source = frame.source();
+        self.fill_quad(corners.map(|x| self.to_screen(x)), |a, b| {
+            let x = (source.x + a * source.w).floor().clamp(0.0, image.width() as f32 - 1.0);
+            let y = (source.y + b * source.h).floor().clamp(0.0, image.height() as f32 - 1.0);
+            let texel = image.get_pixel(x as u32, y as u32);
+            let color = Color::new(texel.r * color.r, texel.g * color.g, texel.b * color.b, texel.a * color.a);
+            (color.a > 0.0).then_some(color)
+        });
+    }
+
+    fn draw_text(&mut self, text: &str, pos: Vec2, font_size: f32, color: Color, align: Align) {
+        let scale = (font_size / GLYPH_SIZE).round().max(1.0);
+        let origin = self.to_screen(pos);
+        let x = match align {
+            Align::Left => origin.x,
+            Align::Center => origin.x - measure_glyphs(text, scale) / 2.0,
+            Align::Right => origin.x - measure_glyphs(text, scale),
+        };
+        let top = origin.y - GLYPH_HEIGHT as f32 * scale;
+        for (i, c) in text.chars().enumerate() {
+            let glyph = glyph(c);
+            for row in 0..GLYPH_HEIGHT {
+                for column in 0..GLYPH_WIDTH {
+                    let bit = (GLYPH_HEIGHT - 1 - row) * GLYPH_WIDTH + (GLYPH_WIDTH - 1 - column);
+                    if glyph & (1 << bit) == 0 {
+                        continue;
+                    }
+                    let x = x + (i * (GLYPH_WIDTH + 1) + column) as f32 * scale;
+                    let y = top + row as f32 * scale;
+                    let corners = [Vec2::new(x, y), Vec2::new(x + scale, y), Vec2::new(x + scale, y + scale), Vec2::new(x, y + scale)];
+                    self.fill_quad(corners, |_, _| Some(color));
+                }
+            }
+        }
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn screen_size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32)
+    }
+
+    fn render(&mut self, commands: &[DrawCommand]) {
+        for command in commands.iter() {
+            match command {
+                DrawCommand::Camera(view) => self.view = Some(*view),
+                DrawCommand::Screen => self.view = None,
+                DrawCommand::Sprite { frame, dest, color, rotation, flip_x, flip_y } => {
+                    let corners = sprite_corners(*dest, *rotation, *flip_x, *flip_y);
+                    self.draw_sprite(frame, corners, *color);
+                }
+                DrawCommand::Rect { rect, color } => self.fill_rect(*rect, *color),
+                DrawCommand::RectLines { rect, thickness, color } => {
+                    let (r, t) = (rect, thickness.min(rect.w / 2.0).min(rect.h / 2.0));
+                    self.fill_rect(Rect::new(r.x, r.y, r.w, t), *color);
+                    self.fill_rect(Rect::new(r.x, r.bottom() - t, r.w, t), *color);
+                    self.fill_rect(Rect::new(r.x, r.y + t, t, r.h - 2.0 * t), *color);
+                    self.fill_rect(Rect::new(r.right() - t, r.y + t, t, r.h - 2.0 * t), *color);
+                }
+                DrawCommand::Line { from, to, thickness, color } => {
+                    let n = (*to - *from).normalize_or_zero().perp() * *thickness / 2.0;
+                    let corners = [*from + n, *to + n, *to - n, *from - n];
+                    self.fill_quad(corners.map(|x| self.to_screen(x)), |_, _| Some(*color));
+                }
+                DrawCommand::Circle { center, radius, color } => {
+                    let c = self.to_screen(*center);
+                    let r = (self.to_screen(*center + Vec2::splat(*radius)) - c).abs();
+                    if r.x <= 0.0 || r.y <= 0.0 {
+                        continue;
+                    }
+                    let pixels: Vec<_> = self.pixels_within(&[c - r, c + r]).collect();
+                    for (x, y, p) in pixels {
+                        if ((p - c) / r).length_squared() <= 1.0 {
+                            self.blend(x, y, *color);
+                        }
+                    }
+                }
+                DrawCommand::Triangle { points, color } => self.fill_triangle(points.map(|x| self.to_screen(x)), *color),
+                DrawCommand::Text { text, pos, font_size, color, align } => self.draw_text(text, *pos, *font_size, *color, *align),
+            }
+        }
+    }
+}
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+/// font size at which glyph pixels are one screen pixel
+const GLYPH_SIZE: f32 = 8.0;
+
+/// Width of the text drawn with the built-in font
+fn measure_glyphs(text: &str, scale: f32) -> f32 {
+    let count = text.chars().count();
+    (count * (GLYPH_WIDTH + 1)).saturating_sub(1) as f32 * scale
+}
+
+/// Bitmap of a character of the built-in font, rows of three pixels from the top.
+/// Lowercase letters are drawn as uppercase, unknown characters as a question mark.
+fn glyph(c: char) -> u16 {
+    match c.to_ascii_uppercase() {
+        'A' => 0b010_101_111_101_101,
+        'B' => 0b110_101_110_101_110,
+        'C' => 0b011_100_100_100_011,
+        'D' => 0b110_101_101_101_110,
+        'E' => 0b111_100_110_100_111,
+        'F' => 0b111_100_110_100_100,
+        'G' => 0b011_100_101_101_011,
+        'H' => 0b101_101_111_101_101,
+        'I' => 0b111_010_010_010_111,
+        'J' => 0b001_001_001_101_010,
+        'K' => 0b101_101_110_101_101,
+        'L' => 0b100_100_100_100_111,
+        'M' => 0b101_111_111_101_101,
+        'N' => 0b110_101_101_101_101,
+        'O' => 0b010_101_101_101_010,
+        'P' => 0b110_101_110_100_100,
+        'Q' => 0b010_101_101_110_011,
+        'R' => 0b110_101_110_101_101,
+        'S' => 0b011_100_010_001_110,
+        'T' => 0b111_010_010_010_010,
+        'U' => 0b101_101_101_101_111,
+        'V' => 0b101_101_101_101_010,
+        'W' => 0b101_101_111_111_101,
+        'X' => 0b101_101_010_101_101,
+        'Y' => 0b101_101_010_010_010,
+        'Z' => 0b111_001_010_100_111,
+        '0' => 0b111_101_101_101_111,
+        '1' => 0b010_110_010_010_111,
+        '2' => 0b110_001_010_100_111,
+        '3' => 0b110_001_010_001_110,
+        '4' => 0b101_101_111_001_001,
+        '5' => 0b111_100_110_001_110,
+        '6' => 0b011_100_111_101_111,
+        '7' => 0b111_001_010_010_010,
+        '8' => 0b111_101_111_101_111,
+        '9' => 0b111_101_111_001_110,
+        ' ' => 0,
+        '.' => 0b000_000_000_000_010,
+        ',' => 0b000_000_000_010_100,
+        ':' => 0b000_010_000_010_000,
+        '!' => 0b010_010_010_000_010,
+        '-' => 0b000_000_111_000_000,
+        '+' => 0b000_010_111_010_000,
+        '=' => 0b000_111_000_111_000,
+        '/' => 0b001_001_010_100_100,
+        '(' => 0b001_010_010_010_001,
+        ')' => 0b100_010_010_010_100,
+        '_' => 0b000_000_000_000_111,
+        '%' => 0b101_001_010_100_101,
+        '\'' => 0b010_010_000_000_000,
+        _ => 0b110_001_010_000_010,
+    }
+}
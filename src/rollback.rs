@@ -0,0 +1,420 @@
+//! Rollback netcode for peer-to-peer play.
+//!
+//! Every tick the `State` is saved before it is simulated with a fixed time step. Inputs of remote
+//! players which have not arrived yet are predicted to be the same as their last known input,
+//! without its one-shot weapon switch and restart. When the actual input arrives and differs from
+//! the prediction, the state is restored to the tick of the input and every tick since is
+//! re-simulated. Peers exchange checksums of ticks for which every input is known, such that a
+//! desync is detected.
+//!
+//! A session has exactly `PEERS` peers, each controlling one player.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::ErrorKind,
+    net::SocketAddr,
+};
+
+use crate::{systems, Context, NetConditions, NetMessage, NetSocket, PlayerInput, State, StateSnapshot};
+
+/// Seconds simulated by each tick
+pub const FRAME_DT: f32 = 1.0 / 60.0;
+
+/// Number of ticks which can be rolled back, and how far ahead of the remote peer the simulation can run
+pub const MAX_ROLLBACK: u32 = 8;
+
+/// Number of recent local inputs sent with every `PeerInput`, such that a lost packet is recovered
+const INPUT_REDUNDANCY: u32 = 8;
+
+/// Number of peers in a session, the player of a peer is its index
+pub const PEERS: usize = 2;
+
+/// Checksums of a tick which differed between the peers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Desync {
+    pub frame: u32,
+    pub local: u64,
+    pub remote: u64,
+}
+
+struct SavedFrame {
+    frame: u32,
+    /// state at the start of the frame
+    state: State,
+    /// inputs of the players used to simulate the frame
+    inputs: Vec<PlayerInput>,
+}
+
+/// Saves states and inputs of recent ticks, such that they can be re-simulated
+pub struct Rollback {
+    /// tick which is simulated next
+    pub frame: u32,
+    saved: VecDeque<SavedFrame>,
+}
+
+pub struct Peer {
+    pub socket: NetSocket,
+    pub remote: SocketAddr,
+    /// index of the player controlled by this peer
+    pub player: usize,
+    pub rollback: Rollback,
+    local_inputs: BTreeMap<u32, PlayerInput>,
+    remote_inputs: BTreeMap<u32, PlayerInput>,
+    /// newest tick up to which every remote input is known
+    confirmed: Option<u32>,
+    /// newest tick whose checksum has been sent
+    checksummed: Option<u32>,
+    /// checksums of ticks for which every input is known
+    checksums: BTreeMap<u32, u64>,
+    remote_checksums: BTreeMap<u32, u64>,
+    /// first tick where the checksum of the remote peer differed
+    pub desync: Option<Desync>,
+    accumulator: f32,
+}
+
+impl Default for Rollback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rollback {
+    pub fn new() -> Self {
+        Self {
+            frame: 0,
+            saved: VecDeque::new(),
+        }
+    }
+
+    /// Saves the state and simulates a single tick using the inputs
+    pub fn advance(&mut self, c: &mut Context, inputs: Vec<PlayerInput>) {
+        self.saved.push_back(SavedFrame {
+            frame: self.frame,
+            state: c.state.clone(),
+            inputs: inputs.clone(),
+        });
+        while self.saved.len() > MAX_ROLLBACK as usize + 1 {
+            self.saved.pop_front();
+        }
+        c.dt = FRAME_DT;
+        c.player_inputs = inputs;
+        systems::simulate(c);
+        self.frame += 1;
+    }
+
+    /// Replaces the input of a player in a tick which has already been simulated.
+    ///
+    /// Returns false if the tick is too old to be corrected.
+    pub fn correct(&mut self, frame: u32, player: usize, input: PlayerInput) -> bool {
+        let Some(saved) = self.saved.iter_mut().find(|x| x.frame == frame) else {
+            return false;
+        };
+        if let Some(x) = saved.inputs.get_mut(player) {
+            *x = input;
+        }
+        true
+    }
+
+    /// Restores the state of the tick and re-simulates every tick since, using the saved inputs
+    pub fn resimulate(&mut self, c: &mut Context, from: u32) {
+        let Some(start) = self.saved.iter().position(|x| x.frame == from) else {
+            return;
+        };
+        let saved: Vec<SavedFrame> = self.saved.drain(start..).collect();
+        let to = self.frame;
+        self.frame = from;
+        c.state = saved[0].state.clone();
+        // the shakes of these ticks were emitted when they were first simulated
+        let shakes = c.shake_events.len();
+        for frame in saved.into_iter() {
+            self.advance(c, frame.inputs);
+        }
+        c.shake_events.truncate(shakes);
+        debug_assert_eq!(self.frame, to);
+    }
+
+    /// State at the start of the tick, if it is still saved
+    pub fn state(&self, frame: u32) -> Option<&State> {
+        self.saved.iter().find(|x| x.frame == frame).map(|x| &x.state)
+    }
+
+    /// Inputs of the players used to simulate the tick, if it is still saved
+    pub fn inputs(&self, frame: u32) -> Option<&[PlayerInput]> {
+        self.saved.iter().find(|x| x.frame == frame).map(|x| x.inputs.as_slice())
+    }
+
+    /// Input of the player used to simulate the tick, if it is still saved
+    pub fn input(&self, frame: u32, player: usize) -> Option<&PlayerInput> {
+        self.saved.iter().find(|x| x.frame == frame).and_then(|x| x.inputs.get(player))
+    }
+}
+
+impl Peer {
+    /// Fails if `player` is not the index of one of the `PEERS` peers
+    pub fn new(addr: &str, remote: &str, player: usize, conditions: NetConditions) -> std::io::Result<Self> {
+        let remote = remote
+            .parse()
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "invalid peer address"))?;
+        Self::from_socket(NetSocket::bind(addr, conditions)?, remote, player)
+    }
+
+    /// Fails if `player` is not the index of one of the `PEERS` peers
+    pub fn from_socket(socket: NetSocket, remote: SocketAddr, player: usize) -> std::io::Result<Self> {
+        if player >= PEERS {
+            let msg = format!("player {} is not one of the {} peers", player, PEERS);
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, msg));
+        }
+        Ok(Self {
+            socket,
+            remote,
+            player,
+            rollback: Rollback::new(),
+            local_inputs: BTreeMap::new(),
+            remote_inputs: BTreeMap::new(),
+            confirmed: None,
+            checksummed: None,
+            checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            desync: None,
+            accumulator: 0.0,
+        })
+    }
+
+    /// Index of the player controlled by the remote peer
+    pub fn remote_player(&self) -> usize {
+        1 - self.player
+    }
+
+    /// Tick following the newest tick up to which every remote input is known
+    fn unconfirmed(&self) -> u32 {
+        self.confirmed.map(|x| x + 1).unwrap_or(0)
+    }
+
+    /// Receives inputs and checksums from the remote peer, rolls back when a prediction was wrong,
+    /// and simulates the ticks which are due using the local input
+    pub fn update(&mut self, c: &mut Context, local_input: PlayerInput) {
+        let remote_player = self.remote_player();
+        let mut rollback_to = None;
+        for (addr, msg) in self.socket.recv() {
+            if addr != self.remote {
+                continue;
+            }
+            match msg {
+                NetMessage::PeerInput { frame, inputs } => {
+                    let first = (frame + 1).saturating_sub(inputs.len() as u32);
+                    for (i, input) in inputs.into_iter().enumerate() {
+                        let frame = first + i as u32;
+                        if frame < self.unconfirmed() || self.remote_inputs.contains_key(&frame) {
+                            continue;
+                        }
+                        if let Some(predicted) = self.rollback.input(frame, remote_player) {
+                            if *predicted != input {
+                                rollback_to = Some(rollback_to.map_or(frame, |x: u32| x.min(frame)));
+                            }
+                        }
+                        self.remote_inputs.insert(frame, input);
+                    }
+                    while self.remote_inputs.contains_key(&self.unconfirmed()) {
+                        self.confirmed = Some(self.unconfirmed());
+                    }
+                }
+                NetMessage::Checksum { frame, checksum } => {
+                    self.remote_checksums.insert(frame, checksum);
+                }
+                _ => {}
+            }
+        }
+        if let Some(from) = rollback_to {
+            // the inputs since the misprediction are predicted again using what is now known
+            for frame in from..self.rollback.frame {
+                let input = self.predicted(frame);
+                self.rollback.correct(frame, remote_player, input);
+            }
+            self.rollback.resimulate(c, from);
+        }
+
+        self.accumulator += c.dt;
+        while self.accumulator >= FRAME_DT {
+            let frame = self.rollback.frame;
+            if frame >= self.unconfirmed() + MAX_ROLLBACK {
+                // wait for the remote peer to catch up, resending the newest inputs in case they
+                // were lost while the remote peer is waiting for them as well
+                self.accumulator = 0.0;
+                if let Some(newest) = frame.checked_sub(1) {
+                    self.send_inputs(newest);
+                }
+                break;
+            }
+            self.accumulator -= FRAME_DT;
+            self.local_inputs.insert(frame, local_input.clone());
+            let mut inputs = vec![PlayerInput::default(); PEERS];
+            inputs[self.player] = local_input.clone();
+            inputs[remote_player] = self.predicted(frame);
+            self.rollback.advance(c, inputs);
+            self.send_inputs(frame);
+        }
+
+        self.exchange_checksums(c);
+    }
+
+    /// Input of the remote player in the tick, predicted from the last known input if not received
+    fn predicted(&self, frame: u32) -> PlayerInput {
+        match self.remote_inputs.range(..=frame).next_back() {
+            Some((known, input)) if *known == frame => input.clone(),
+            Some((_, input)) => input.predicted(),
+            None => PlayerInput::default(),
+        }
+    }
+
+    fn send_inputs(&mut self, frame: u32) {
+        let first = frame.saturating_sub(INPUT_REDUNDANCY - 1);
+        let inputs = self.local_inputs.range(first..=frame).map(|x| x.1.clone()).collect();
+        self.socket.send(self.remote, &NetMessage::PeerInput { frame, inputs });
+        while self.local_inputs.len() > (INPUT_REDUNDANCY + MAX_ROLLBACK) as usize {
+            self.local_inputs.pop_first();
+        }
+    }
+
+    /// Sends checksums of ticks whose state only depends on known inputs and compares them with the
+    /// checksums of the remote peer
+    fn exchange_checksums(&mut self, c: &mut Context) {
+        // the state at the start of a tick only depends on the inputs of earlier ticks
+        let last = self.unconfirmed().min(self.rollback.frame);
+        let first = self.checksummed.map(|x| x + 1).unwrap_or(0);
+        for frame in first..=last {
+            let state = match frame == self.rollback.frame {
+                true => Some(&c.state),
+                false => self.rollback.state(frame),
+            };
+            if let Some(state) = state {
+                let snapshot = StateSnapshot::create_snapshot(state, &c.metadata);
+                let checksum = snapshot.checksum();
+                self.checksums.insert(frame, checksum);
+                self.socket.send(self.remote, &NetMessage::Checksum { frame, checksum });
+                // the state at the start of a tick is the result of the previous tick
+                if let (Some(trace), Some(tick)) = (c.trace.as_mut(), frame.checked_sub(1)) {
+                    trace.tick = tick;
+                    let inputs = self.rollback.inputs(tick).unwrap_or_default();
+                    trace.record(FRAME_DT, inputs, snapshot);
+                }
+            }
+            self.checksummed = Some(frame);
+        }
+
+        for (frame, checksum) in self.remote_checksums.iter() {
+            let Some(local) = self.checksums.get(frame) else {
+                continue;
+            };
+            if local != checksum && self.desync.is_none() {
+                self.desync = Some(Desync { frame: *frame, local: *local, remote: *checksum });
+            }
+        }
+        let oldest = last.saturating_sub(MAX_ROLLBACK * 4);
+        self.checksums.retain(|x, _| *x >= oldest);
+        self.remote_checksums.retain(|x, _| *x >= oldest);
+        // the newest confirmed input is kept for predictions
+        let oldest = self.unconfirmed().saturating_sub(1);
+        self.remote_inputs.retain(|x, _| *x >= oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use glam::Vec2;
+
+    use super::*;
+    use crate::{InputSource, Metadata, NetClock, Rng};
+
+    /// Context of a session of `PEERS` players which has just been started
+    fn context() -> Context {
+        let mut c = Context {
+            metadata: Metadata::from_disk(&[], false).unwrap(),
+            inputs: vec![InputSource::Network; PEERS],
+            ..Default::default()
+        };
+        systems::once(&mut c);
+        c
+    }
+
+    fn checksum(c: &Context) -> u64 {
+        StateSnapshot::create_snapshot(&c.state, &c.metadata).checksum()
+    }
+
+    /// Input of the player in the tick, changing every few ticks
+    fn input(player: usize, frame: u32) -> PlayerInput {
+        let turn = (frame / 20 + player as u32) as f32;
+        PlayerInput {
+            locomotion_dir: Vec2::new(turn.cos(), turn.sin()),
+            attack_dir: match (frame / 15) % 2 == player as u32 {
+                true => Vec2::new(-turn.sin(), turn.cos()),
+                false => Vec2::ZERO,
+            },
+            weapon: frame.is_multiple_of(40).then_some((frame / 40) as usize % 4),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resimulating_corrected_inputs_matches_the_correct_simulation() {
+        let frames = MAX_ROLLBACK;
+        let mut correct = context();
+        let mut rollback = Rollback::new();
+        for frame in 0..frames {
+            rollback.advance(&mut correct, vec![input(0, frame), input(1, frame)]);
+        }
+
+        // the remote player is predicted to stand still
+        let mut predicted = context();
+        let mut rollback = Rollback::new();
+        for frame in 0..frames {
+            rollback.advance(&mut predicted, vec![input(0, frame), PlayerInput::default()]);
+        }
+        assert_ne!(checksum(&predicted), checksum(&correct));
+
+        for frame in 0..frames {
+            assert!(rollback.correct(frame, 1, input(1, frame)));
+        }
+        rollback.resimulate(&mut predicted, 0);
+
+        assert_eq!(rollback.frame, frames);
+        assert_eq!(checksum(&predicted), checksum(&correct));
+        // the oldest tick is dropped once the window is full
+        rollback.advance(&mut predicted, vec![input(0, frames), input(1, frames)]);
+        rollback.advance(&mut predicted, vec![input(0, frames + 1), input(1, frames + 1)]);
+        assert!(!rollback.correct(0, 1, PlayerInput::default()));
+    }
+
+    #[test]
+    fn peers_with_latency_and_packet_loss_stay_in_sync() {
+        let time = Rc::new(Cell::new(0.0));
+        let clock: NetClock = {
+            let time = time.clone();
+            Rc::new(move || time.get())
+        };
+        let conditions = NetConditions { latency: 0.05, packet_loss: 0.2 };
+        let socket = |seed| NetSocket::bind_with("127.0.0.1:0", conditions, clock.clone(), Rng::new(seed)).unwrap();
+        let (a, b) = (socket(1), socket(2));
+        let (addr_a, addr_b) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let mut peers = [Peer::from_socket(a, addr_b, 0).unwrap(), Peer::from_socket(b, addr_a, 1).unwrap()];
+        let mut contexts = [context(), context()];
+
+        for _ in 0..360 {
+            time.set(time.get() + FRAME_DT as f64);
+            for (peer, c) in peers.iter_mut().zip(contexts.iter_mut()) {
+                c.dt = FRAME_DT;
+                let frame = peer.rollback.frame;
+                peer.update(c, input(peer.player, frame));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        for peer in peers.iter() {
+            assert_eq!(peer.desync, None);
+            assert!(peer.unconfirmed() > 240, "only {} ticks are confirmed", peer.unconfirmed());
+            // checksums of the same ticks were compared
+            assert!(peer.checksums.keys().any(|x| peer.remote_checksums.contains_key(x)));
+        }
+    }
+}
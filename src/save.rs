@@ -0,0 +1,575 @@
+//! Versioned save files.
+//!
+//! A save starts with `SAVE_MAGIC` and the format version, followed by the bincode of a `SaveInfo`
+//! and of the `StateSnapshot` as it was defined in that version. Saves of older versions are migrated to the
+//! current `StateSnapshot` when read. Saves without a header are of version 0, written before the
+//! format was versioned.
+//!
+//! When the snapshot changes in a way which breaks existing saves, such as a field being added to
+//! `ActorState`, the current definitions are frozen in a module `vN`, `SAVE_VERSION` is incremented
+//! and a migration from the frozen definitions is added to `decode_save`.
+//!
+//! Saves can also be written as RON, a readable text format which can be edited by hand. The text
+//! format always matches the current definitions and is not migrated.
+
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ActorHandle, ActorSnapshot, ActorState, Animation, Clock, GameState, MeleeState, Rng, StateSnapshot, Timer};
+
+/// First bytes of every versioned save
+pub const SAVE_MAGIC: [u8; 4] = *b"GVZS";
+
+/// Version of the saves written by this build
+pub const SAVE_VERSION: u32 = 4;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    /// the save was written by a newer build
+    UnsupportedVersion(u32),
+    /// the save is truncated or not a save at all
+    Corrupt(String),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "{}", e),
+            SaveError::UnsupportedVersion(v) => write!(f, "save version {} is newer than {}", v, SAVE_VERSION),
+            SaveError::Corrupt(e) => write!(f, "save is corrupt: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for SaveError {
+    fn from(e: bincode::Error) -> Self {
+        SaveError::Corrupt(e.to_string())
+    }
+}
+
+/// Definitions of the snapshot before saves were versioned
+mod v0 {
+    use glam::{Vec2, Vec4};
+    use serde::Deserialize;
+
+    use crate::ActorHandle;
+
+    #[derive(Deserialize)]
+    pub struct Timer {
+        pub timer: f32,
+        pub end_time: f32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Clock {
+        pub tick: f32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Rect {
+        pub left: f32,
+        pub top: f32,
+        pub width: f32,
+        pub height: f32,
+    }
+
+    #[derive(Deserialize)]
+    pub enum GameState {
+        Countdown { timer: Timer },
+        Spawning { mobs_left_to_spawn: u32, mobs_total: u32 },
+        WaitForDefeat,
+        WaitForReadyToRespawn { timer: Timer },
+        ReadyToRespawn,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ActorState {
+        pub weapon_cooldown: f32,
+        pub pos: Vec2,
+        pub locomotion_dir: Vec2,
+        pub vel: Vec2,
+        pub attack_dir: Vec2,
+        pub owner: ActorHandle,
+        pub health: f32,
+        pub color: Vec4,
+        pub pain_timer: Timer,
+        pub frame: f32,
+        pub facing: f32,
+        pub age: f32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ActorSnapshot {
+        pub info: String,
+        pub weapon: String,
+        pub state: ActorState,
+    }
+
+    #[derive(Deserialize)]
+    pub struct StateSnapshot {
+        pub spawner: Clock,
+        pub me: usize,
+        pub game_state: GameState,
+        pub round: u32,
+        pub actors: Vec<ActorSnapshot>,
+        pub bounds: Rect,
+    }
+}
+
+/// Definitions of the snapshot of versions 1 and 2, before actors had animation clips.
+/// Types which have not changed since are used as they are.
+mod v2 {
+    use glam::{Vec2, Vec4};
+    use serde::Deserialize;
+
+    use crate::{state::Rect, ActorHandle, Clock, GameState, MeleeState, Rng, StatusEffect, Timer};
+
+    #[derive(Deserialize)]
+    pub struct ActorState {
+        pub weapon_cooldown: f32,
+        pub pos: Vec2,
+        pub locomotion_dir: Vec2,
+        pub vel: Vec2,
+        pub attack_dir: Vec2,
+        pub owner: ActorHandle,
+        pub health: f32,
+        pub color: Vec4,
+        pub pain_timer: Timer,
+        pub frame: f32,
+        pub facing: f32,
+        pub age: f32,
+        pub effects: Vec<StatusEffect>,
+        pub hit_actors: Vec<ActorHandle>,
+        pub pierced: u32,
+        pub bounced: u32,
+        pub melee: MeleeState,
+        pub dead_time: f32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ActorSnapshot {
+        pub handle: ActorHandle,
+        pub info: String,
+        pub weapon: String,
+        pub state: ActorState,
+    }
+
+    #[derive(Deserialize)]
+    pub struct StateSnapshot {
+        pub spawner: Clock,
+        pub players: Vec<usize>,
+        pub game_state: GameState,
+        pub round: u32,
+        pub actors: Vec<ActorSnapshot>,
+        pub bounds: Rect,
+        pub rng: Rng,
+    }
+}
+
+/// Definitions of the snapshot of version 3, before players had scores.
+/// Types which have not changed since are used as they are.
+mod v3 {
+    use serde::Deserialize;
+
+    use crate::{state::Rect, ActorSnapshot, Clock, GameState, Rng};
+
+    #[derive(Deserialize)]
+    pub struct StateSnapshot {
+        pub spawner: Clock,
+        pub players: Vec<usize>,
+        pub game_state: GameState,
+        pub round: u32,
+        pub actors: Vec<ActorSnapshot>,
+        pub bounds: Rect,
+        pub rng: Rng,
+    }
+}
+
+fn migrate_timer(t: v0::Timer) -> Timer {
+    Timer {
+        timer: t.timer,
+        end_time: t.end_time,
+    }
+}
+
+/// Version 0 had a single player, no status effects, missile or melee state and no random state
+fn migrate_v0(s: v0::StateSnapshot) -> v2::StateSnapshot {
+    let actors = s
+        .actors
+        .into_iter()
+        .map(|a| v2::ActorSnapshot {
+            handle: ActorHandle::default(),
+            info: a.info,
+            weapon: a.weapon,
+            state: v2::ActorState {
+                weapon_cooldown: a.state.weapon_cooldown,
+                pos: a.state.pos,
+                locomotion_dir: a.state.locomotion_dir,
+                vel: a.state.vel,
+                attack_dir: a.state.attack_dir,
+                owner: a.state.owner,
+                health: a.state.health,
+                color: a.state.color,
+                pain_timer: migrate_timer(a.state.pain_timer),
+                frame: a.state.frame,
+                facing: a.state.facing,
+                age: a.state.age,
+                effects: Vec::new(),
+                hit_actors: Vec::new(),
+                pierced: 0,
+                bounced: 0,
+                melee: MeleeState::Idle,
+                dead_time: 0.0,
+            },
+        })
+        .collect();
+    v2::StateSnapshot {
+        spawner: Clock { tick: s.spawner.tick },
+        players: vec![s.me],
+        game_state: match s.game_state {
+            v0::GameState::Countdown { timer } => GameState::Countdown { timer: migrate_timer(timer) },
+            v0::GameState::Spawning { mobs_left_to_spawn, mobs_total } => GameState::Spawning { mobs_left_to_spawn, mobs_total },
+            v0::GameState::WaitForDefeat => GameState::WaitForDefeat,
+            v0::GameState::WaitForReadyToRespawn { timer } => GameState::WaitForReadyToRespawn { timer: migrate_timer(timer) },
+            v0::GameState::ReadyToRespawn => GameState::ReadyToRespawn,
+        },
+        round: s.round,
+        actors,
+        bounds: crate::state::Rect {
+            left: s.bounds.left,
+            top: s.bounds.top,
+            width: s.bounds.width,
+            height: s.bounds.height,
+        },
+        rng: Rng::default(),
+    }
+}
+
+/// Version 2 had no animation clips, actors start playing their idle clip
+fn migrate_v2(s: v2::StateSnapshot) -> v3::StateSnapshot {
+    let actors = s
+        .actors
+        .into_iter()
+        .map(|a| ActorSnapshot {
+            handle: a.handle,
+            info: a.info,
+            weapon: a.weapon,
+            state: ActorState {
+                weapon_cooldown: a.state.weapon_cooldown,
+                pos: a.state.pos,
+                locomotion_dir: a.state.locomotion_dir,
+                vel: a.state.vel,
+                attack_dir: a.state.attack_dir,
+                owner: a.state.owner,
+                health: a.state.health,
+                color: a.state.color,
+                pain_timer: a.state.pain_timer,
+                // frame advanced at 10 frames per second
+                animation: Animation {
+                    time: a.state.frame / 10.0,
+                    ..Default::default()
+                },
+                facing: a.state.facing,
+                age: a.state.age,
+                effects: a.state.effects,
+                hit_actors: a.state.hit_actors,
+                pierced: a.state.pierced,
+                bounced: a.state.bounced,
+                melee: a.state.melee,
+                dead_time: a.state.dead_time,
+            },
+        })
+        .collect();
+    v3::StateSnapshot {
+        spawner: s.spawner,
+        players: s.players,
+        game_state: s.game_state,
+        round: s.round,
+        actors,
+        bounds: s.bounds,
+        rng: s.rng,
+    }
+}
+
+/// Version 3 had no scores, players start from zero
+fn migrate_v3(s: v3::StateSnapshot) -> StateSnapshot {
+    StateSnapshot {
+        spawner: s.spawner,
+        scores: vec![0; s.players.len()],
+        players: s.players,
+        game_state: s.game_state,
+        round: s.round,
+        actors: s.actors,
+        bounds: s.bounds,
+        rng: s.rng,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u32,
+}
+
+/// Shown when browsing saves, without decoding the whole save
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SaveInfo {
+    pub round: u32,
+    /// seconds since the unix epoch when the save was written
+    pub timestamp: u64,
+    /// seconds played until the save was written
+    pub playtime: f32,
+    pub summary: String,
+}
+
+/// Save which has been read from a slot or file
+#[derive(Serialize, Deserialize)]
+pub struct SaveFile {
+    pub info: SaveInfo,
+    pub snapshot: StateSnapshot,
+}
+
+/// Save slot found in `saves_dir`
+pub struct SaveSlot {
+    pub name: String,
+    pub info: SaveInfo,
+}
+
+impl SaveInfo {
+    /// Info of a save written now
+    pub fn new(snapshot: &StateSnapshot, playtime: f32, summary: String) -> Self {
+        Self {
+            round: snapshot.round,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            playtime,
+            summary,
+        }
+    }
+
+    /// Timestamp as `YYYY-MM-DD HH:MM` in UTC
+    pub fn date(&self) -> String {
+        let days = (self.timestamp / 86400) as i64;
+        let secs = self.timestamp % 86400;
+        // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs / 3600, secs % 3600 / 60)
+    }
+}
+
+/// Encodes the save with a header of the current version
+pub fn encode_save(info: &SaveInfo, snapshot: &StateSnapshot) -> Vec<u8> {
+    let header = Header {
+        magic: SAVE_MAGIC,
+        version: SAVE_VERSION,
+    };
+    let mut bytes = bincode::serialize(&header).unwrap();
+    bytes.extend(bincode::serialize(info).unwrap());
+    bytes.extend(bincode::serialize(snapshot).unwrap());
+    bytes
+}
+
+/// Splits the save into its version and the bytes following the header
+fn split_header(bytes: &[u8]) -> Result<(u32, &[u8]), SaveError> {
+    if !bytes.starts_with(&SAVE_MAGIC) {
+        return Ok((0, bytes));
+    }
+    let header: Header = bincode::deserialize(bytes)?;
+    let size = bincode::serialized_size(&header)? as usize;
+    Ok((header.version, &bytes[size..]))
+}
+
+/// Decodes a save of any supported version, migrating it to the current `StateSnapshot`
+pub fn decode_save(bytes: &[u8]) -> Result<SaveFile, SaveError> {
+    let (version, body) = split_header(bytes)?;
+    match version {
+        0 => {
+            let snapshot = migrate_v3(migrate_v2(migrate_v0(bincode::deserialize(body)?)));
+            let info = SaveInfo { round: snapshot.round, ..Default::default() };
+            Ok(SaveFile { info, snapshot })
+        }
+        // version 1 had no info
+        1 => {
+            let snapshot = migrate_v3(migrate_v2(bincode::deserialize(body)?));
+            let info = SaveInfo { round: snapshot.round, ..Default::default() };
+            Ok(SaveFile { info, snapshot })
+        }
+        2 => {
+            let info: SaveInfo = bincode::deserialize(body)?;
+            let size = bincode::serialized_size(&info)? as usize;
+            let snapshot = migrate_v3(migrate_v2(bincode::deserialize(&body[size..])?));
+            Ok(SaveFile { info, snapshot })
+        }
+        3 => {
+            let info: SaveInfo = bincode::deserialize(body)?;
+            let size = bincode::serialized_size(&info)? as usize;
+            let snapshot = migrate_v3(bincode::deserialize(&body[size..])?);
+            Ok(SaveFile { info, snapshot })
+        }
+        SAVE_VERSION => {
+            let info: SaveInfo = bincode::deserialize(body)?;
+            let size = bincode::serialized_size(&info)? as usize;
+            let snapshot = bincode::deserialize(&body[size..])?;
+            Ok(SaveFile { info, snapshot })
+        }
+        version => Err(SaveError::UnsupportedVersion(version)),
+    }
+}
+
+/// Decodes only the info of a save
+pub fn decode_save_info(bytes: &[u8]) -> Result<SaveInfo, SaveError> {
+    match split_header(bytes)? {
+        (2..=SAVE_VERSION, body) => Ok(bincode::deserialize(body)?),
+        _ => Ok(decode_save(bytes)?.info),
+    }
+}
+
+/// Encodes the save as RON
+pub fn encode_save_text(save: &SaveFile) -> String {
+    ron::ser::to_string_pretty(save, ron::ser::PrettyConfig::default()).unwrap()
+}
+
+pub fn decode_save_text(text: &str) -> Result<SaveFile, SaveError> {
+    ron::from_str(text).map_err(|e| SaveError::Corrupt(e.to_string()))
+}
+
+/// Saves ending in `.ron` are in the text format, other saves are binary
+fn is_text(path: &Path) -> bool {
+    path.extension().map(|x| x == "ron").unwrap_or_default()
+}
+
+/// Reads a save from a path outside the save slots, in the text or the binary format
+pub fn read_save_file(path: &str) -> Result<SaveFile, SaveError> {
+    match is_text(Path::new(path)) {
+        true => decode_save_text(&std::fs::read_to_string(path)?),
+        false => decode_save(&std::fs::read(path)?),
+    }
+}
+
+/// Writes a save to a path outside the save slots, in the text or the binary format
+pub fn write_save_file(path: &str, save: &SaveFile) -> Result<(), SaveError> {
+    match is_text(Path::new(path)) {
+        true => std::fs::write(path, encode_save_text(save))?,
+        false => std::fs::write(path, encode_save(&save.info, &save.snapshot))?,
+    }
+    Ok(())
+}
+
+/// Directory of the save slots in the data directory of the user
+pub fn saves_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("guyvszombies")
+        .join("saves")
+}
+
+fn slot_path(name: &str) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|x| if x.is_alphanumeric() || x == '-' || x == '_' || x == ' ' { x } else { '_' })
+        .collect();
+    saves_dir().join(format!("{}.sav", name))
+}
+
+pub fn write_slot(name: &str, info: &SaveInfo, snapshot: &StateSnapshot) -> Result<(), SaveError> {
+    std::fs::create_dir_all(saves_dir())?;
+    std::fs::File::create(slot_path(name))?.write_all(&encode_save(info, snapshot))?;
+    Ok(())
+}
+
+pub fn read_slot(name: &str) -> Result<SaveFile, SaveError> {
+    decode_save(&std::fs::read(slot_path(name))?)
+}
+
+pub fn delete_slot(name: &str) -> Result<(), SaveError> {
+    std::fs::remove_file(slot_path(name))?;
+    Ok(())
+}
+
+/// Every readable save slot, newest first
+pub fn list_slots() -> Vec<SaveSlot> {
+    let Ok(entries) = std::fs::read_dir(saves_dir()) else {
+        return Vec::new();
+    };
+    let mut slots = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().map(|x| x != "sav").unwrap_or(true) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        match std::fs::read(&path).map_err(SaveError::from).and_then(|x| decode_save_info(&x)) {
+            Ok(info) => slots.push(SaveSlot { name: name.to_string(), info }),
+            Err(e) => eprintln!("skipping save {}: {}", path.display(), e),
+        }
+    }
+    slots.sort_by_key(|x| std::cmp::Reverse(x.info.timestamp));
+    slots
+}
+
+/// In-game menu for browsing, loading and deleting saves
+#[derive(Default)]
+pub struct SaveMenu {
+    pub open: bool,
+    pub slots: Vec<SaveSlot>,
+    /// selected row, the first row creates a new save and the slots follow
+    pub selected: usize,
+    /// name being typed for a new save
+    pub naming: Option<String>,
+    /// round which has been autosaved
+    pub autosaved_round: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Metadata, State};
+
+    #[test]
+    fn version_3_saves_start_without_scores() {
+        let md = Metadata::from_disk(&[], false).unwrap();
+        let mut state = State::default();
+        let player = state.spawn_actor(md.actors.get("guy").unwrap().clone()).handle;
+        state.players.push(player);
+        state.scores.push(5);
+        let s = StateSnapshot::create_snapshot(&state, &md);
+
+        let mut bytes = bincode::serialize(&Header { magic: SAVE_MAGIC, version: 3 }).unwrap();
+        bytes.extend(bincode::serialize(&SaveInfo::default()).unwrap());
+        // the fields of the snapshot of version 3, in order
+        bytes.extend(bincode::serialize(&(&s.spawner, &s.players, &s.game_state, s.round, &s.actors, &s.bounds, &s.rng)).unwrap());
+        let save = decode_save(&bytes).unwrap();
+        assert_eq!(save.snapshot.scores, vec![0]);
+        assert_eq!(save.snapshot.actors.len(), 1);
+
+        let save = decode_save(&encode_save(&SaveInfo::default(), &s)).unwrap();
+        assert_eq!(save.snapshot.scores, vec![5]);
+    }
+}
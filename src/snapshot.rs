@@ -1,36 +1,58 @@
 //! Contains serializable data structures that captures the runtime state of the game
 
+use std::{collections::HashMap, fmt};
+
 use serde::{Deserialize, Serialize};
-use slotmap::SlotMap;
+use slotmap::{Key, SlotMap};
 
-use crate::{Actor, ActorHandle, ActorState, Clock, GameState, Metadata, State, state::Rect};
+use crate::{Actor, ActorHandle, ActorState, Clock, GameState, Metadata, Rng, State, state::Rect};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ActorSnapshot {
+    /// handle of the actor when the snapshot was created
+    #[serde(default)]
+    pub handle: ActorHandle,
     pub info: String,
     pub weapon: String,
     pub state: ActorState,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StateSnapshot {
     pub spawner: Clock,
-    pub me: usize,
+    /// indices into `actors` of the actors controlled by the players
+    pub players: Vec<usize>,
+    /// score of each player, indexed by player
+    #[serde(default)]
+    pub scores: Vec<u32>,
     pub game_state: GameState,
     pub round: u32,
     pub actors: Vec<ActorSnapshot>,
-    pub bounds: Rect
+    pub bounds: Rect,
+    pub rng: Rng,
 }
 
 impl StateSnapshot {
+    /// Stable hash (FNV-1a) of the snapshot, equal snapshots have equal checksums across machines
+    pub fn checksum(&self) -> u64 {
+        let bytes = bincode::serialize(self).unwrap();
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in bytes.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
     pub fn create_snapshot(state: &State, _md: &Metadata) -> StateSnapshot {
         let mut actor_snapshots = Vec::default();
-        let mut me = 0;
+        let mut players = vec![0; state.players.len()];
         for (handle, actor) in state.actors.iter() {
-            if state.me == handle {
-                me = actor_snapshots.len();
+            if let Some(player) = state.players.iter().position(|x| *x == handle) {
+                players[player] = actor_snapshots.len();
             }
             actor_snapshots.push(ActorSnapshot {
+                handle,
                 info: actor.info.name.clone(),
                 weapon: actor.weapon.name.clone(),
                 state: actor.state.clone(),
@@ -38,36 +60,169 @@ impl StateSnapshot {
         }
         StateSnapshot {
             spawner: state.spawner.clone(),
-            me,
+            players,
+            scores: state.scores.clone(),
             game_state: state.game_state.clone(),
             round: state.round,
             actors: actor_snapshots,
-            bounds: state.bounds
+            bounds: state.bounds,
+            rng: state.rng.clone(),
         }
     }
     
-    pub fn load_snapshot(&self, md: &Metadata) -> State {
+    /// Creates the state of the snapshot.
+    ///
+    /// Actors and effects which are no longer defined are dropped and unknown weapons are replaced
+    /// by the default weapon of the actor, such that saves keep loading after content updates.
+    /// Handles stored in the actors are rewritten to the handles of the new state.
+    pub fn load_snapshot(&self, md: &Metadata) -> (State, Vec<SnapshotWarning>) {
+        let mut warnings = Vec::new();
         let mut actors = SlotMap::default();
-        let mut me = ActorHandle::default();
+        let mut handles = HashMap::new();
+        let mut players = vec![ActorHandle::default(); self.players.len()];
         for (index, actor) in self.actors.iter().enumerate() {
+            let Some(info) = md.actors.get(&actor.info) else {
+                warnings.push(SnapshotWarning::DroppedActor(actor.info.clone()));
+                continue;
+            };
+            let weapon = match md.weapons.get(&actor.weapon) {
+                Some(weapon) => weapon.clone(),
+                None => {
+                    warnings.push(SnapshotWarning::ReplacedWeapon { actor: actor.info.clone(), weapon: actor.weapon.clone() });
+                    info.weapon.clone()
+                }
+            };
+            let mut state = actor.state.clone();
+            state.effects.retain_mut(|effect| match md.effects.get(&effect.info.name) {
+                Some(info) => {
+                    effect.info = info.clone();
+                    true
+                }
+                None => {
+                    warnings.push(SnapshotWarning::DroppedEffect { actor: actor.info.clone(), effect: effect.info.name.clone() });
+                    false
+                }
+            });
             let handle = actors.insert_with_key(|handle| Actor {
                 handle,
-                info: md.actors.get(&actor.info).unwrap().clone(),
-                weapon: md.weapons.get(&actor.weapon).unwrap().clone(),
-                state: actor.state.clone(),
+                info: info.clone(),
+                weapon,
+                state,
             });
-            if index == self.me {
-                me = handle;
+            // saves written before handles were stored have null handles
+            if !actor.handle.is_null() {
+                handles.insert(actor.handle, handle);
             }
+            if let Some(player) = self.players.iter().position(|x| *x == index) {
+                players[player] = handle;
+            }
+        }
+        for actor in actors.values_mut() {
+            actor.owner = handles.get(&actor.owner).copied().unwrap_or_default();
+            actor.hit_actors = actor.hit_actors.iter().filter_map(|x| handles.get(x).copied()).collect();
         }
-        State {
+        let mut scores = self.scores.clone();
+        scores.resize(players.len(), 0);
+        let state = State {
             spawner: self.spawner.clone(),
-            me,
+            players,
+            scores,
             actors,
             contact_events: Default::default(),
+            death_events: Default::default(),
+            animation_events: Default::default(),
             round: self.round,
             game_state: self.game_state.clone(),
-            bounds: self.bounds
+            bounds: self.bounds,
+            rng: self.rng.clone(),
+        };
+        (state, warnings)
+    }
+}
+
+/// Content of a snapshot which could not be loaded as it was
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotWarning {
+    /// the actor is no longer defined in actors.toml and was left out
+    DroppedActor(String),
+    /// the weapon is no longer defined in weapons.toml and the default weapon of the actor was used
+    ReplacedWeapon { actor: String, weapon: String },
+    /// the effect is no longer defined in effects.toml and was removed from the actor
+    DroppedEffect { actor: String, effect: String },
+}
+
+impl fmt::Display for SnapshotWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotWarning::DroppedActor(name) => write!(f, "dropped unknown actor \"{}\"", name),
+            SnapshotWarning::ReplacedWeapon { actor, weapon } => write!(f, "replaced unknown weapon \"{}\" of \"{}\" with its default weapon", weapon, actor),
+            SnapshotWarning::DroppedEffect { actor, effect } => write!(f, "removed unknown effect \"{}\" of \"{}\"", effect, actor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::EffectInfo;
+
+    #[test]
+    fn load_rewrites_handles() {
+        let md = Metadata::from_disk(&[], false).unwrap();
+        let mut state = State::default();
+        // padding which is removed before saving, such that the loaded state hands out other handles
+        let padding: Vec<ActorHandle> = (0..3).map(|_| state.spawn_actor(md.actors.get("zombie").unwrap().clone()).handle).collect();
+        let guy = state.spawn_actor(md.actors.get("guy").unwrap().clone()).handle;
+        let zombie = state.spawn_actor(md.actors.get("zombie").unwrap().clone()).handle;
+        let bullet = state.spawn_actor(md.actors.get("bullet").unwrap().clone());
+        bullet.owner = guy;
+        bullet.hit_actors = vec![zombie];
+        let bullet = bullet.handle;
+        for handle in padding {
+            state.actors.remove(handle);
         }
+
+        let snapshot = StateSnapshot::create_snapshot(&state, &md);
+        let (loaded, warnings) = snapshot.load_snapshot(&md);
+        assert!(warnings.is_empty());
+        let find = |name: &str| loaded.actors.values().find(|x| x.info.name == name).unwrap().handle;
+        let (new_guy, new_zombie, new_bullet) = (find("guy"), find("zombie"), find("bullet"));
+        assert_ne!(new_guy, guy);
+        assert_ne!(new_bullet, bullet);
+        let new_bullet = loaded.actor(new_bullet).unwrap();
+        assert_eq!(new_bullet.owner, new_guy);
+        assert_eq!(new_bullet.hit_actors, vec![new_zombie]);
+    }
+
+    #[test]
+    fn load_drops_unknown_content() {
+        let md = Metadata::from_disk(&[], false).unwrap();
+        let mut state = State::default();
+        let guy = state.spawn_actor(md.actors.get("guy").unwrap().clone()).handle;
+        state.players = vec![guy];
+        state.spawn_actor(md.actors.get("zombie").unwrap().clone());
+
+        state.actor_mut(guy).unwrap().apply_effect(md.effects.get("slowed").unwrap());
+        state.actor_mut(guy).unwrap().apply_effect(md.effects.get("burning").unwrap());
+
+        // snapshots only hold the names of effects, the loaded effects are those of the metadata
+        let bytes = bincode::serialize(&StateSnapshot::create_snapshot(&state, &md)).unwrap();
+        let mut snapshot: StateSnapshot = bincode::deserialize(&bytes).unwrap();
+        snapshot.actors[0].weapon = "flamethrower".to_owned();
+        snapshot.actors[0].state.effects[1].info = Rc::new(EffectInfo { name: "frozen".to_owned(), ..Default::default() });
+        snapshot.actors[1].info = "fist".to_owned();
+        let (loaded, warnings) = snapshot.load_snapshot(&md);
+        assert_eq!(warnings, vec![
+            SnapshotWarning::ReplacedWeapon { actor: "guy".to_owned(), weapon: "flamethrower".to_owned() },
+            SnapshotWarning::DroppedEffect { actor: "guy".to_owned(), effect: "frozen".to_owned() },
+            SnapshotWarning::DroppedActor("fist".to_owned()),
+        ]);
+        assert_eq!(loaded.actors.len(), 1);
+        let player = loaded.actor(loaded.players[0]).unwrap();
+        assert_eq!(player.weapon.name, player.info.weapon.name);
+        assert_eq!(player.effects.len(), 1);
+        assert!(Rc::ptr_eq(&player.effects[0].info, md.effects.get("slowed").unwrap()));
     }
 }
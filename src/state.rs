@@ -2,41 +2,141 @@ use glam::{Vec2, Vec4};
 use serde::{Deserialize, Serialize};
 use slotmap::{new_key_type, SlotMap};
 use std::{
+    cell::Cell,
+    fmt,
     ops::{Deref, DerefMut},
     rc::Rc,
 };
 
-use crate::{ActorInfo, WeaponInfo};
+use crate::{ActorInfo, Clip, EffectInfo, Metadata, Stacking, WeaponInfo};
 
 new_key_type! {
     pub struct ActorHandle;
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct Timer {
     pub timer: f32,
     pub end_time: f32,
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct Clock {
     pub tick: f32,
 }
 
+/// Deterministic random number generator (xorshift64*).
+///
+/// Part of the `State` such that the simulation can be replayed and re-simulated.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Rng {
+    state: Cell<u64>,
+}
+
+/// A timed status effect active on an actor, such as burning or slowed
 #[derive(Clone, Serialize, Deserialize)]
+pub struct StatusEffect {
+    /// only the name is serialized, `StateSnapshot::load_snapshot` links the `EffectInfo` of the metadata
+    #[serde(rename = "effect", with = "effect_name")]
+    pub info: Rc<EffectInfo>,
+    pub timer: Timer,
+    pub stacks: u32,
+}
+
+impl PartialEq for StatusEffect {
+    fn eq(&self, other: &Self) -> bool {
+        self.info.name == other.info.name && self.timer == other.timer && self.stacks == other.stacks
+    }
+}
+
+impl fmt::Debug for StatusEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatusEffect")
+            .field("effect", &self.info.name)
+            .field("timer", &self.timer)
+            .field("stacks", &self.stacks)
+            .finish()
+    }
+}
+
+/// Serializes an `EffectInfo` as its name, it is deserialized as an effect without any effect
+mod effect_name {
+    use std::rc::Rc;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::EffectInfo;
+
+    pub fn serialize<S: Serializer>(info: &Rc<EffectInfo>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&info.name)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rc<EffectInfo>, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(Rc::new(EffectInfo { name, ..Default::default() }))
+    }
+}
+
+/// Playback of the animation clip of an actor
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Animation {
+    pub clip: Clip,
+    /// seconds since the clip was started
+    pub time: f32,
+    /// the clip was started since the last tick, the events of its first frame are still to be emitted
+    pub started: bool,
+}
+
+/// Progress of a melee attack
+#[derive(Clone, Default, Serialize, Deserialize, PartialEq, Debug)]
+pub enum MeleeState {
+    #[default]
+    Idle,
+    /// the attack is telegraphed and hits when the timer is done
+    WindUp { timer: Timer },
+    /// the attack has been performed and the actor recovers
+    Recovery { timer: Timer },
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct ActorState {
+    #[serde(default)]
     pub weapon_cooldown: f32,
     pub pos: Vec2,
+    #[serde(default)]
     pub locomotion_dir: Vec2,
+    #[serde(default)]
     pub vel: Vec2,
+    #[serde(default)]
     pub attack_dir: Vec2,
+    #[serde(default)]
     pub owner: ActorHandle,
     pub health: f32,
     pub color: Vec4,
+    #[serde(default)]
     pub pain_timer: Timer,
-    pub frame: f32,
+    #[serde(default)]
+    pub animation: Animation,
+    #[serde(default)]
     pub facing: f32,
+    #[serde(default)]
     pub age: f32,
+    #[serde(default)]
+    pub effects: Vec<StatusEffect>,
+    /// actors already hit by this missile
+    #[serde(default)]
+    pub hit_actors: Vec<ActorHandle>,
+    /// number of actors this missile has pierced
+    #[serde(default)]
+    pub pierced: u32,
+    /// number of times this missile has bounced
+    #[serde(default)]
+    pub bounced: u32,
+    #[serde(default)]
+    pub melee: MeleeState,
+    /// time since the actor died
+    #[serde(default)]
+    pub dead_time: f32,
 }
 
 #[derive(Clone)]
@@ -47,7 +147,7 @@ pub struct Actor {
     pub state: ActorState,
 }
 
-#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Debug)]
 pub struct Rect {
     pub left: f32,
     pub top: f32,
@@ -55,14 +155,21 @@ pub struct Rect {
     pub height: f32,
 }
 
+#[derive(Clone)]
 pub struct State {
     pub spawner: Clock,
-    pub me: ActorHandle,
+    /// actors controlled by the local players, indexed by player
+    pub players: Vec<ActorHandle>,
+    /// number of enemies killed by each player, indexed by player
+    pub scores: Vec<u32>,
     pub actors: SlotMap<ActorHandle, Actor>,
     pub contact_events: Vec<ContactEvent>,
+    pub death_events: Vec<DeathEvent>,
+    pub animation_events: Vec<AnimationEvent>,
     pub round: u32,
     pub game_state: GameState,
     pub bounds: Rect,
+    pub rng: Rng,
 }
 
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -78,7 +185,24 @@ pub enum ContactEvent {
     },
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Emitted when the animation of an actor reaches a frame with an event
+#[derive(Clone)]
+pub struct AnimationEvent {
+    pub actor: ActorHandle,
+    pub event: String,
+}
+
+/// Emitted when an actor is killed
+#[derive(Clone)]
+pub struct DeathEvent {
+    pub actor: ActorHandle,
+    /// actor responsible for the killing blow, if any
+    pub killer: ActorHandle,
+    /// damage dealt beyond what was needed to kill the actor
+    pub overkill: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub enum GameState {
     Countdown {
         timer: Timer,
@@ -159,6 +283,40 @@ impl Cooldown {
     }
 }
 
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0x853c49e6748fea9b)
+    }
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Cell::new(seed.max(1)),
+        }
+    }
+
+    pub fn next_u32(&self) -> u32 {
+        let mut x = self.state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.set(x);
+        (x.wrapping_mul(0x2545f4914f6cdd1d) >> 32) as u32
+    }
+
+    pub fn f32_0_1(&self) -> f32 {
+        let v = self.next_u32() as f32;
+        v / u32::MAX as f32
+    }
+
+    pub fn f32_1_1(&self) -> f32 {
+        let v = self.f32_0_1();
+        let v = v - 0.5;
+        v * 2.0
+    }
+}
+
 impl Clock {
     pub fn tick(&mut self, dt: f32, reset_at: f32) -> bool {
         self.tick += dt;
@@ -193,6 +351,33 @@ impl Actor {
         self.health > 0.0
     }
 
+    /// Starts the clip from its first frame
+    pub fn play(&mut self, clip: Clip) {
+        self.animation = Animation { clip, time: 0.0, started: true };
+    }
+
+    /// Clip the actor should be playing, clips without frames are skipped.
+    /// An attack is played until it is finished.
+    pub fn wanted_clip(&self) -> Clip {
+        let has_frames = |clip| !self.info.clip(clip).frames.is_empty();
+        if !self.is_alive() {
+            return match has_frames(Clip::Die) {
+                true => Clip::Die,
+                false => Clip::Idle,
+            };
+        }
+        if self.animation.clip == Clip::Attack && !self.info.clip(Clip::Attack).is_finished(self.animation.time) {
+            return Clip::Attack;
+        }
+        if !self.pain_timer.is_done() && has_frames(Clip::Pain) {
+            return Clip::Pain;
+        }
+        if self.locomotion_dir.length() > 0.0 && has_frames(Clip::Walk) {
+            return Clip::Walk;
+        }
+        Clip::Idle
+    }
+
     pub fn is_solid(&self) -> bool {
         if !self.is_alive() {
             return false;
@@ -201,17 +386,72 @@ impl Actor {
         self.info.solid
     }
 
+    /// The actors are on the same team, such that they do not hit each other
+    pub fn is_ally(&self, other: &Actor) -> bool {
+        !self.info.team.is_empty() && self.info.team == other.info.team
+    }
+
     pub fn hand_pos(&self) -> Vec2 {
         let pos = self.pos;
         let v = self.facing_vector();
         pos + v * self.info.radius
     }
 
+    /// Seconds since the weapon was last fired, while its firing frames are shown
+    pub fn weapon_firing(&self) -> Option<f32> {
+        let since = 1.0 / self.weapon.rate_of_fire - self.weapon_cooldown;
+        (self.weapon_cooldown > 0.0 && since < self.weapon.firing_time).then_some(since)
+    }
+
     pub fn muzzle_pos(&self) -> Vec2 {
         let hand = self.hand_pos();
         let v = self.facing_vector();
         hand + v * self.weapon.muzzle_offset
     }
+
+    /// Reflects the velocity of the actor around the normal and counts the bounce
+    pub fn bounce(&mut self, normal: Vec2) {
+        let vel = self.vel;
+        self.vel = vel - 2.0 * vel.dot(normal) * normal;
+        self.facing = f32::atan2(self.vel.y, self.vel.x);
+        self.bounced += 1;
+    }
+
+    /// Applies the effect to the actor, following the stacking rules of the effect
+    pub fn apply_effect(&mut self, effect_info: &Rc<EffectInfo>) {
+        if let Some(effect) = self.effects.iter_mut().find(|x| x.info.name == effect_info.name) {
+            match effect_info.stacking {
+                Stacking::Refresh => effect.timer.restart(effect_info.duration),
+                Stacking::Stack => {
+                    effect.stacks = (effect.stacks + 1).min(effect_info.max_stacks.max(1));
+                    effect.timer.restart(effect_info.duration);
+                }
+                Stacking::Ignore => {}
+            }
+            return;
+        }
+
+        self.effects.push(StatusEffect {
+            info: effect_info.clone(),
+            timer: Timer::start(effect_info.duration),
+            stacks: 1,
+        });
+    }
+
+    /// Combined speed multiplier of the active effects
+    pub fn speed_multiplier(&self) -> f32 {
+        self.effects.iter().map(|x| x.info.speed_multiplier).product()
+    }
+
+    /// True if an active effect prevents the actor from attacking
+    pub fn is_stunned(&self) -> bool {
+        self.effects.iter().any(|x| x.info.stun)
+    }
+
+    /// Combined tint of the active effects
+    pub fn effects_tint(&self) -> Vec4 {
+        self.effects.iter().map(|x| x.info.tint).fold(Vec4::ONE, |a, b| a * b)
+    }
 }
 
 impl Default for GameState {
@@ -238,12 +478,16 @@ impl Default for State {
         let h = 15.0;
         Self {
             spawner: Default::default(),
-            me: Default::default(),
+            players: Default::default(),
+            scores: Default::default(),
             actors: Default::default(),
             contact_events: Default::default(),
+            death_events: Default::default(),
+            animation_events: Default::default(),
             round: Default::default(),
             game_state: Default::default(),
             bounds: Rect { left: -w / 2.0, top: -h / 2.0, width: w, height: h },
+            rng: Default::default(),
         }
     }
 }
@@ -263,6 +507,69 @@ impl State {
         left
     }
 
+    /// Position of the living actor of the player, if any
+    pub fn player_pos(&self, player: usize) -> Option<Vec2> {
+        let actor = self.actor(*self.players.get(player)?)?;
+        actor.is_alive().then_some(actor.pos)
+    }
+
+    pub fn is_player(&self, handle: ActorHandle) -> bool {
+        self.players.contains(&handle)
+    }
+
+    /// True if any of the players is alive
+    pub fn players_alive(&self) -> bool {
+        self.players
+            .iter()
+            .filter_map(|x| self.actor(*x))
+            .any(|x| x.is_alive())
+    }
+
+    /// Returns the nearest living player, or any player if every player is dead
+    pub fn nearest_player(&self, pos: Vec2) -> Option<&Actor> {
+        let players = self.players.iter().filter_map(|x| self.actor(*x));
+        let nearest = |a: &&Actor, b: &&Actor| a.pos.distance(pos).total_cmp(&b.pos.distance(pos));
+        players
+            .clone()
+            .filter(|x| x.is_alive())
+            .min_by(nearest)
+            .or_else(|| players.min_by(nearest))
+    }
+
+    /// Reduces the health of the actor and emits a `DeathEvent` if the damage killed it
+    pub fn damage(&mut self, handle: ActorHandle, killer: ActorHandle, dmg: f32) {
+        let Some(actor) = self.actor_mut(handle) else {
+            return;
+        };
+        if !actor.is_alive() {
+            return;
+        }
+        actor.health -= dmg;
+        if !actor.is_alive() {
+            let overkill = -actor.health;
+            self.death_events.push(DeathEvent { actor: handle, killer, overkill });
+        }
+    }
+
+    /// Re-points the actors to the infos of the metadata with the same names, keeping their state.
+    ///
+    /// Actors whose info, weapon or effects no longer exist keep the old ones.
+    pub fn relink(&mut self, md: &Metadata) {
+        for actor in self.actors.values_mut() {
+            if let Some(info) = md.actors.get(&actor.info.name) {
+                actor.info = info.clone();
+            }
+            if let Some(weapon) = md.weapons.get(&actor.weapon.name) {
+                actor.weapon = weapon.clone();
+            }
+            for effect in actor.state.effects.iter_mut() {
+                if let Some(info) = md.effects.get(&effect.info.name) {
+                    effect.info = info.clone();
+                }
+            }
+        }
+    }
+
     pub fn despawn_actor(&mut self, handle: ActorHandle) {
         self.actors.remove(handle);
     }
@@ -280,10 +587,16 @@ impl State {
                 owner: Default::default(),
                 color: Vec4::new(1.0, 1.0, 1.0, 1.0),
                 pain_timer: Timer::stop(0.25),
-                frame: 0.0,
+                animation: Animation::default(),
                 facing: 0.0,
                 weapon_cooldown: 0.0,
                 age: 0.0,
+                effects: Vec::new(),
+                hit_actors: Vec::new(),
+                pierced: 0,
+                bounced: 0,
+                melee: MeleeState::Idle,
+                dead_time: 0.0,
             },
             info: actor_info,
             weapon,
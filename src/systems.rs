@@ -1,23 +1,35 @@
 
-use std::{f32::consts::PI, io::{Write, Read}};
+use std::{f32::consts::PI, rc::Rc};
 
-use crate::{Context, ContactEvent, GameState, Timer, StateSnapshot, State};
+use crate::{Actor, Align, Peer, ShakeEvent, AnimationEvent, Clip, Context, DrawList, View, world_to_screen, ContactEvent, EffectInfo, GameState, MeleeState, Timer, StateSnapshot, State, ActorHandle, InputSource, Metadata, Net, NetMessage, PlayerInput, RemoteClient, SaveFile, SaveInfo, delete_slot, list_slots, read_slot, write_slot};
 use macroquad::prelude::*;
 
 
-/// Updates the camera based upon the size of the screen, by ensuring zoom is set to the correct level
+/// Updates the camera based upon the size of the screen, by ensuring zoom is set to the correct level.
+/// Follows the living players, looking ahead toward where they aim, and zooms out such that every
+/// living player is framed. Shakes the camera for the `ShakeEvent` of local players.
 pub fn camera(c: &mut Context) {
-    let width = screen_width();
-    let height = screen_height();
-    let aspect = width / height;
-    let size = 12.0;
-
-    let zoom = 1.0 / size;
-
-    c.camera.zoom = Vec2::new(zoom, zoom * aspect);
+    for ev in std::mem::take(&mut c.shake_events) {
+        let Some(player) = c.state.players.iter().position(|x| *x == ev.actor) else { continue; };
+        if matches!(c.inputs.get(player), None | Some(InputSource::Network)) {
+            continue;
+        }
+        c.camera_rig.add_trauma(ev.trauma);
+    }
+    let aspect = c.draw_list.screen.x / c.draw_list.screen.y;
+    let players: Vec<&Actor> = c.state.players.iter()
+        .filter_map(|x| c.state.actor(*x))
+        .filter(|x| x.is_alive())
+        .collect();
+    let positions: Vec<Vec2> = players.iter().map(|x| x.pos).collect();
+    let aims: Vec<Vec2> = players.iter().map(|x| x.facing_vector()).collect();
+    c.camera_rig.update(&positions, &aims, &c.state.bounds, aspect, c.dt);
+    c.camera_rig.apply(&mut c.camera, aspect);
 }
 
-/// Updates all bots, ensuring their bot logic has run and that the corrosponding bot actors have been updated
+/// Updates all bots, ensuring their bot logic has run and that the corrosponding bot actors have been updated.
+/// Bots target the nearest living player. Bots keep facing the direction of a melee attack which is
+/// winding up, such that it can be dodged.
 pub fn bots(c: &mut Context) {
     for actor in c.state.actor_handles() {
         let Some(bot) = c.state.actor(actor) else {
@@ -29,7 +41,7 @@ pub fn bots(c: &mut Context) {
         if !bot.info.bot {
             continue;
         }
-        let Some(player) = c.state.actor(c.state.me) else {
+        let Some(player) = c.state.nearest_player(bot.pos) else {
             continue;
         };
         let player_is_alive = player.is_alive();
@@ -39,14 +51,16 @@ pub fn bots(c: &mut Context) {
        
         let bot = c.state.actor_mut(actor).unwrap();
         if player_is_alive {
-            bot.facing = f32::atan2(direction_to_player.y, direction_to_player.x);
+            if matches!(bot.melee, MeleeState::Idle) {
+                bot.facing = f32::atan2(direction_to_player.y, direction_to_player.x);
+            }
             bot.locomotion_dir = direction_to_player;
         } else {
             bot.locomotion_dir = Default::default();
         }
         
-        if player_is_alive && range_to_player < bot.weapon.range && player_is_alive {
-            bot.attack_dir = v.normalize_or_zero().clone();
+        if player_is_alive && range_to_player < bot.weapon.range {
+            bot.attack_dir = v.normalize_or_zero();
         } else {
             bot.attack_dir = Default::default();
         }
@@ -54,9 +68,10 @@ pub fn bots(c: &mut Context) {
 }
 
 pub fn draw(c: &mut Context) {
-    set_camera(&c.camera);
+    let list = &mut c.draw_list;
+    list.set_camera(&c.camera);
     let s = 32.0;
-    draw_rectangle(-s / 2.0, -s / 2.0, s, s, DARKGRAY);
+    list.rect(-s / 2.0, -s / 2.0, s, s, DARKGRAY);
     let mut sorted_actors = Vec::new();
 
     for actor in c.state.actor_handles() {
@@ -65,34 +80,19 @@ pub fn draw(c: &mut Context) {
         };
         sorted_actors.push(actor);
     }
-    sorted_actors.sort_by(|a, b|a.pos.y.partial_cmp(&b.pos.y).unwrap());
+    sorted_actors.sort_by(|a, b| a.pos.y.total_cmp(&b.pos.y));
 
 
     for actor in sorted_actors.drain(..) {
-        let mut frames = &actor.info.frames;
-        if actor.locomotion_dir.length() > 0.0 {
-            frames = &actor.info.locomotion_frames;
-        }
-        if actor.health <= 0.0 {
-            frames = &actor.info.dead_frames;
-        }
-        if frames.is_empty() {
-            frames = &actor.info.frames;
-        }
-
-        if frames.is_empty() {
+        let clip = actor.info.clip(actor.animation.clip);
+        let Some(frame) = clip.frame(actor.animation.time) else {
             continue;
-        }
-        let f = actor.frame as usize % frames.len();
-       
-        let frame: &crate::ImageIndex = &frames[f];
-        
-        let img = &frame.image;
-        let texture = &img.texture;
+        };
+
         let size = Vec2::new(2.0, 2.0);
         let x: f32 = actor.pos.x - size.x / 2.0 + actor.info.offset.x;
         let y = actor.pos.y - size.y / 2.0 + actor.info.offset.y;
-        let color:[f32;4] = actor.color.into();
+        let color:[f32;4] = (actor.color * actor.effects_tint()).into();
         let flip_x = match actor.info.rotate_to_face {
             true => false,
             false => actor.facing_vector().x < 0.0,
@@ -101,198 +101,264 @@ pub fn draw(c: &mut Context) {
             true => actor.facing,
             false => 0.0,
         };
-        draw_texture_ex(
-            texture,
-            x,
-            y,
-            color.into(),
-            DrawTextureParams {
-                dest_size: Some(size),
-                flip_x,
-                rotation,
-                ..Default::default()
-            },
-        );
+        list.sprite(frame, Rect::new(x, y, size.x, size.y), color.into(), rotation, flip_x, false);
+
+        // telegraph melee attacks which are winding up
+        if let MeleeState::WindUp { timer } = &actor.melee {
+            let hand = actor.hand_pos();
+            let reach = actor.weapon.melee_reach;
+            let arc = actor.weapon.melee_arc;
+            let color = Color::new(1.0, 0.0, 0.0, 0.5 * timer.alpha());
+            let segments = 8;
+            for i in 0..segments {
+                let a1 = actor.facing - arc / 2.0 + arc * i as f32 / segments as f32;
+                let a2 = actor.facing - arc / 2.0 + arc * (i + 1) as f32 / segments as f32;
+                let p1 = hand + Vec2::new(a1.cos(), a1.sin()) * reach;
+                let p2 = hand + Vec2::new(a2.cos(), a2.sin()) * reach;
+                list.triangle(hand, p1, p2, color);
+            }
+        }
 
         // only draw weapons for alive actors
         if actor.is_alive() {
             let weapon_info = actor.weapon.clone();
-            let texture = weapon_info.frames.get(0);
-            if let Some(frame) = texture {
-                let image = &frame.image;
+            let firing = actor.weapon_firing();
+            if let Some(frame) = weapon_info.frame(firing) {
                 let v = actor.facing_vector();
                 let hand = actor.hand_pos();
                 let mount: Vec2 =  hand - size / 2.0 + v * weapon_info.mount_offset * size.length();
                 
-                draw_texture_ex(&image.texture, mount.x, mount.y, WHITE, DrawTextureParams {
-                    dest_size: Some(size),
-                    rotation:actor.facing,
-                    flip_y:v.x < 0.0,
-                    ..Default::default()
-                });
+                list.sprite(frame, Rect::new(mount.x, mount.y, size.x, size.y), WHITE, actor.facing, false, v.x < 0.0);
     
                 if c.debug {
                     let muzzle = actor.muzzle_pos();
-                    draw_circle(hand.x, hand.y, 0.1, GREEN);
-                    draw_circle(muzzle.x, muzzle.y, 0.1, RED);
+                    list.circle(hand, 0.1, GREEN);
+                    list.circle(muzzle, 0.1, RED);
                 }
             }
+            if let Some(time) = firing {
+                draw_muzzle_flash(list, actor, time);
+            }
         }
     }
 
 }
 
+/// Draws the muzzle flash sprite and light of the weapon of an actor `time` seconds after it was fired.
+/// The light fades out during the firing time of the weapon.
+fn draw_muzzle_flash(list: &mut DrawList, actor: &crate::Actor, time: f32) {
+    let weapon_info = &actor.weapon;
+    let muzzle = actor.muzzle_pos();
+    let fade = 1.0 - time / weapon_info.firing_time;
+    let light = weapon_info.muzzle_light;
+    if light.w > 0.0 {
+        // layered discs approximate a soft falloff
+        let layers = 4;
+        for i in 0..layers {
+            let radius = weapon_info.muzzle_light_radius * (layers - i) as f32 / layers as f32;
+            let color = Color::new(light.x, light.y, light.z, light.w * fade / layers as f32);
+            list.circle(muzzle, radius, color);
+        }
+    }
+    if let Some(frame) = weapon_info.muzzle_flash_frame(time) {
+        let size = Vec2::splat(weapon_info.muzzle_flash_size);
+        let pos = muzzle - size / 2.0;
+        list.sprite(frame, Rect::new(pos.x, pos.y, size.x, size.y), WHITE, actor.facing, false, false);
+    }
+}
+
 /// Draw the bounds of the game.
 fn draw_bounds(c:&mut Context) {
     let b = c.state.bounds;
-    let top_left = c.camera.world_to_screen([b.left, b.top].into());
-    let bottom_right = c.camera.world_to_screen([b.right(), b.bottom()].into());
-    set_default_camera();
+    let list = &mut c.draw_list;
+    let (width, height) = (list.screen.x, list.screen.y);
+    let view = View { target: c.camera.target, zoom: c.camera.zoom };
+    let top_left = world_to_screen(&view, list.screen, [b.left, b.top].into());
+    let bottom_right = world_to_screen(&view, list.screen, [b.right(), b.bottom()].into());
+    list.set_screen();
     let color = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.8 };
-    draw_rectangle(top_left.x, 0.0, bottom_right.x - top_left.x, top_left.y, color);
-    draw_rectangle(top_left.x, bottom_right.y, bottom_right.x - top_left.x, screen_height() - bottom_right.y, color);
-    draw_rectangle(0.0, 0.0, top_left.x, screen_height(), color);
-    draw_rectangle(bottom_right.x, 0.0, screen_width() - top_left.x, screen_height(), color);
+    list.rect(top_left.x, 0.0, bottom_right.x - top_left.x, top_left.y, color);
+    list.rect(top_left.x, bottom_right.y, bottom_right.x - top_left.x, height - bottom_right.y, color);
+    list.rect(0.0, 0.0, top_left.x, height, color);
+    list.rect(bottom_right.x, 0.0, width - bottom_right.x, height, color);
+}
+
+
+/// Draws an infinity sign centered on `center` with line segments, as the fonts have no glyph for it
+fn draw_infinity(list: &mut DrawList, center: Vec2, width: f32, color: Color) {
+    // lemniscate of Bernoulli
+    let point = |t: f32| {
+        let d = 1.0 + t.sin() * t.sin();
+        center + Vec2::new(t.cos(), t.sin() * t.cos()) * width / 2.0 / d
+    };
+    let segments = 24;
+    for i in 0..segments {
+        let a = point(i as f32 / segments as f32 * PI * 2.0);
+        let b = point((i + 1) as f32 / segments as f32 * PI * 2.0);
+        list.line(a, b, 2.0, color);
+    }
 }
 
+/// Draws a horizontal bar filled from the left by `fraction`, from 0 to 1
+fn draw_bar(list: &mut DrawList, rect: Rect, fraction: f32, color: Color) {
+    list.rect(rect.x, rect.y, rect.w, rect.h, Color::new(0.0, 0.0, 0.0, 0.6));
+    list.rect(rect.x, rect.y, rect.w * fraction.clamp(0.0, 1.0), rect.h, color);
+    list.rect_lines(rect.x, rect.y, rect.w, rect.h, 2.0, WHITE);
+}
 
+/// Draws the round, the zombies left and the progress of spawning at the top of the screen and
+/// the health and weapon of every player at the bottom left
 pub fn draw_hud(c:&mut Context) {
-    set_default_camera();
-    let x = screen_width() / 2.0;
-    let font_size = 32;
-    let y = font_size as f32;
+    let list = &mut c.draw_list;
+    list.set_screen();
+    let screen = list.screen;
+    let center = screen / 2.0;
+    let font_size = 32.0;
+    let small_font_size = 24.0;
+    let margin = 16.0;
     let s = format!("ROUND {}", &c.state.round);
-    let m = measure_text(&s, None, font_size, 1.0);
-    draw_text(&s, x - m.width / 2.0, y, font_size as f32, WHITE);
-
-    fn draw_text_center(str:&str, font_size: u16) {
-        let x = screen_width() / 2.0;
-        let y = screen_height() / 2.0;
-        let m = measure_text(str, None, font_size, 1.0);
-        draw_text(str, x - m.width / 2.0, y, font_size as f32, WHITE);
-    }
+    list.text(&s, Vec2::new(center.x, font_size), font_size, WHITE, Align::Center);
+    let s = format!("ZOMBIES LEFT {}", c.state.mobs_left());
+    list.text(&s, Vec2::new(center.x, font_size + small_font_size + 8.0), small_font_size, WHITE, Align::Center);
 
     match &c.state.game_state {
+        GameState::Spawning { mobs_left_to_spawn, mobs_total } if *mobs_total > 0 => {
+            let spawned = (mobs_total - mobs_left_to_spawn) as f32 / *mobs_total as f32;
+            let width = 200.0;
+            let rect = Rect::new(center.x - width / 2.0, font_size + small_font_size + 20.0, width, 10.0);
+            draw_bar(list, rect, spawned, ORANGE);
+        }
         GameState::Countdown { timer } => {
             let s = format!("Next round starting in {:.2} seconds", &timer.time_left());
-            draw_text_center(&s, font_size);
+            list.text(&s, center, font_size, WHITE, Align::Center);
         },
         GameState::ReadyToRespawn => {
-            draw_text_center(&"You died! Click to restart!", font_size);
+            list.text("You died! Click to restart!", center, font_size, WHITE, Align::Center);
         }
         _ => {}
     }
+    if let Some(Net::Peer(Peer { desync: Some(desync), .. })) = &c.net {
+        let s = format!("DESYNC AT TICK {}", desync.frame);
+        list.text(&s, Vec2::new(center.x, center.y + font_size * 2.0), font_size, RED, Align::Center);
+    }
+
+    let bar = Vec2::new(200.0, 16.0);
+    for (index, handle) in c.state.players.iter().enumerate().rev() {
+        let Some(player) = c.state.actor(*handle) else {
+            continue;
+        };
+        let row = (c.state.players.len() - 1 - index) as f32;
+        let bottom = screen.y - margin - row * (bar.y + small_font_size + margin);
+        let health = player.health.max(0.0) / player.info.health;
+        let color = match health > 0.3 {
+            true => GREEN,
+            false => RED,
+        };
+        let rect = Rect::new(margin, bottom - bar.y, bar.x, bar.y);
+        draw_bar(list, rect, health, color);
+        let s = format!("{}/{}", player.health.max(0.0).ceil(), player.info.health);
+        list.text(&s, Vec2::new(margin + bar.x + 8.0, bottom), small_font_size, WHITE, Align::Left);
+        let name = bottom - bar.y - 6.0;
+        let s = match player.is_alive() {
+            true => format!("P{} {}", index + 1, player.weapon.display_name),
+            false => format!("P{} DEAD", index + 1),
+        };
+        list.text(&s, Vec2::new(margin, name), small_font_size, WHITE, Align::Left);
+        let score = c.state.scores.get(index).copied().unwrap_or_default();
+        list.text(&format!("SCORE {}", score), Vec2::new(margin + bar.x + 8.0, name), small_font_size, WHITE, Align::Left);
+        // weapons have unlimited ammo
+        if player.is_alive() {
+            let x = margin + bar.x + 8.0 + 128.0;
+            list.text("AMMO", Vec2::new(x, bottom), small_font_size, WHITE, Align::Left);
+            let width = small_font_size;
+            let center = Vec2::new(x + small_font_size * 2.0 + 8.0 + width / 2.0, bottom - small_font_size / 3.0);
+            draw_infinity(list, center, width, WHITE);
+        }
+    }
 }
 
 fn draw_debug(c:&mut Context) {
     if !c.debug { return };
+    let list = &mut c.draw_list;
+    list.set_screen();
+    let s = format!("FPS {}", get_fps());
+    list.text(&s, Vec2::new(list.screen.x - 16.0, 24.0), 24.0, GRAY, Align::Right);
+    list.set_camera(&c.camera);
     for actor_handle in c.state.actor_handles() {
         let Some(actor) = c.state.actor(actor_handle) else { continue;};
         let r = actor.info.radius;
         let x = actor.pos.x - r;
         let y = actor.pos.y - r;
-        draw_rectangle_lines(x, y, r * 2.0, r * 2.0, 0.1, RED);
+        list.rect_lines(x, y, r * 2.0, r * 2.0, 0.1, RED);
         let v = Vec2::new(actor.facing.cos(), actor.facing.sin());
-        draw_line(actor.pos.x, actor.pos.y, actor.pos.x + v.x, actor.pos.y + v.y, 0.05, GREEN);
+        list.line(actor.pos, actor.pos + v, 0.05, GREEN);
     }
 
     let b = c.state.bounds;
-    draw_rectangle_lines(b.left, b.top, b.width, b.height, 0.1, RED);
+    list.rect_lines(b.left, b.top, b.width, b.height, 0.1, RED);
 }
 
-/// Collects input from the player and update the player actor based upon this input
-fn player(c: &mut Context) {
-    let mut d = Vec2::new(0.0, 0.0);
+/// Weapons selected by the weapon slots of `PlayerInput`
+const WEAPON_SLOTS: [&str; 4] = ["fists", "pistol", "machinegun", "rifle"];
+
+/// Collects the input of every player from their `InputSource` into `Context::player_inputs`
+fn inputs(c: &mut Context) {
     if is_key_pressed(KeyCode::F1) {
         c.debug = !c.debug;
     }
-    if is_key_down(KeyCode::A) {
-        d.x = -1.0;
-    }
-    if is_key_down(KeyCode::D) {
-        d.x = 1.0;
-    }
-    if is_key_down(KeyCode::W) {
-        d.y = -1.0;
-    }
-    if is_key_down(KeyCode::S) {
-        d.y = 1.0;
+    c.player_inputs.clear();
+    for (index, input_source) in c.inputs.iter().enumerate() {
+        let input = match (input_source, &mut c.net) {
+            (InputSource::Network, Some(Net::Server(server))) => server.take_input(index),
+            _ => input_source.read(&c.camera, c.state.player_pos(index), &c.gamepads),
+        };
+        c.player_inputs.push(input);
     }
+}
 
-    let mut attack_dir = Vec2::new(0.0, 0.0);
-    if is_key_down(KeyCode::Left) {
-        attack_dir.x = -1.0;
-    }
-    if is_key_down(KeyCode::Right) {
-        attack_dir.x = 1.0;
-    }
-    if is_key_down(KeyCode::Up) {
-        attack_dir.y = -1.0;
-    }
-    if is_key_down(KeyCode::Down) {
-        attack_dir.y = 1.0;
-    }
+/// Updates the player actors based upon `Context::player_inputs`
+fn player(c: &mut Context) {
+    for (index, input) in c.player_inputs.iter().enumerate() {
+        let Some(handle) = c.state.players.get(index).copied() else {
+            continue;
+        };
+        let Some(player) = c.state.actor_mut(handle) else {
+            continue;
+        };
+        if !player.is_alive() {
+            continue;
+        }
 
-    let d = d.normalize_or_zero();
-    let Some(player) = c.state.actor_mut(c.state.me) else {
-        return;
-    };
+        if let Some(weapon) = input.weapon.and_then(|x| WEAPON_SLOTS.get(x)).and_then(|x| c.metadata.weapons.get(*x)) {
+            player.weapon = weapon.clone();
+        }
 
+        let mut attack_dir = input.attack_dir;
+        if attack_dir.length() > 0.0 {
+            player.facing = f32::atan2(attack_dir.y, attack_dir.x);
+        } else if let Some(v) = input.aim_dir() {
+            player.facing = f32::atan2(v.y, v.x);
+            if input.fire {
+                attack_dir = v;
+            }
+        } else if input.locomotion_dir.length() > 0.0 {
+            let v = input.locomotion_dir;
+            player.facing = f32::atan2(v.y, v.x);
+        }
 
-    if player.is_alive() == false {
-        return;
-    }
-    
-    if is_key_pressed(KeyCode::Key1) {
-        player.weapon = c.metadata.weapons.get("fists").unwrap().clone()
-    }
-    if is_key_pressed(KeyCode::Key2) {
-        player.weapon = c.metadata.weapons.get("pistol").unwrap().clone();
-    }
-    if is_key_pressed(KeyCode::Key3) {
-        player.weapon = c.metadata.weapons.get("machinegun").unwrap().clone();
-    }
-    if is_key_pressed(KeyCode::Key4) {
-        player.weapon = c.metadata.weapons.get("rifle").unwrap().clone();
-    }
-    if is_key_pressed(KeyCode::Key5) {
+        player.attack_dir = attack_dir;
+        player.locomotion_dir = input.locomotion_dir;
     }
-    if is_key_pressed(KeyCode::Key6) {
-    }
-    if is_key_pressed(KeyCode::Key7) {
-    }
-    if is_key_pressed(KeyCode::Key8) {
-    }
-    if is_key_pressed(KeyCode::Key9) {
-    }
-    
-    if attack_dir.length() == 0.0 {
-        // check mouse
-        let m = mouse_position();
-        let w = c.camera.screen_to_world(m.into());
-        let v = w - player.pos;
-        let v = v.normalize_or_zero();
-        let a = f32::atan2(v.y, v.x);
-        player.facing = a;
-        if is_mouse_button_down(MouseButton::Left) {
-            let v = v.normalize_or_zero();
-            attack_dir = v;
-        }
-    }
-
-    player.attack_dir = attack_dir;
-    player.locomotion_dir = d;
-    
 }
 
 fn locomotion(c: &mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
     for handle in c.state.actor_handles() {
         let actor = c.state.actor_mut(handle).unwrap();
         if !actor.is_alive() {
             actor.locomotion_dir = Vec2::default();
         }
-        let speed = actor.info.speed;
+        let speed = actor.info.speed * actor.speed_multiplier();
         let max_acceleration = speed * speed * dt;
         let desired_vel = actor.locomotion_dir * speed;
         let delta_vel = desired_vel - actor.vel;
@@ -321,7 +387,7 @@ fn physics(c: &mut Context) {
         spatial.insert([pos.x, pos.y], *handle);
     }
     // TODO apply substeps
-    let dt = get_frame_time();
+    let dt = c.dt;
     for handle in actor_handles.drain(..) {
         let actor = c.state.actor(handle).unwrap();
         let vel = actor.vel;
@@ -329,6 +395,8 @@ fn physics(c: &mut Context) {
         let pos = actor.pos;
         let mut new_pos = pos + vel * dt;
 
+        // missiles are not pushed back, such that they can pierce through their targets
+        let push = !actor.info.missile;
         if actor.is_solid() {
             let shape = parry2d::shape::Cuboid::new([actor.info.radius, actor.info.radius].into());
             let q = spatial.query_around([pos.x, pos.y], 2.0);
@@ -367,7 +435,9 @@ fn physics(c: &mut Context) {
                     };
 
                     let push_back = Vec2::new(contact.normal1.x, contact.normal1.y) * contact.dist;
-                    new_pos += push_back;
+                    if push {
+                        new_pos += push_back;
+                    }
                     // TODO maybe avoid generating multiple contact events
                     let ce = ContactEvent::Actor { actor: handle, other_actor: handle2 };
                     c.state.contact_events.push(ce);
@@ -383,52 +453,134 @@ fn physics(c: &mut Context) {
 /// Updates and handle actors whom are attacking with their weapons. 
 /// Ensures that projectiles are spawned based upon the attack state.
 fn attack(c:&mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
     for actor in c.state.actor_handles() {
-        let Some(actor) = c.state.actor_mut(actor) else { continue;};
-        if actor.is_alive() == false {
+        let Some(actor) = c.state.actors.get_mut(actor) else { continue;};
+        if !actor.is_alive() {
             continue;
         }
         actor.weapon_cooldown -= dt;
         if actor.weapon_cooldown < 0.0 {
             actor.weapon_cooldown = 0.0;
         }
-        if actor.attack_dir.length() > 0.0 {
+        if actor.attack_dir.length() > 0.0 && !actor.is_stunned() {
             let weapon_info = actor.weapon.clone();
-            if actor.weapon_cooldown == 0.0 {
+            if actor.weapon_cooldown == 0.0 && matches!(actor.melee, MeleeState::Idle) {
                 actor.weapon_cooldown = 1.0 / weapon_info.rate_of_fire;
-                if let Some(projectile_actor_info) = c.metadata.actors.get(&weapon_info.projectile) {
-                    let speed = projectile_actor_info.velocity;
-                    let spawn_pos = actor.muzzle_pos();
-                    let spread = rand_f32_1_1() * weapon_info.spread;
-                    let facing_with_spread = actor.facing + spread;
-                    let d = Vec2::new(facing_with_spread.cos(), facing_with_spread.sin());
-                    let v = d * speed;
-                    let bullet = c.state.spawn_actor(projectile_actor_info.clone());
-                    bullet.pos = spawn_pos;
-                    bullet.vel = v;
-                    bullet.facing = facing_with_spread;
+                let attack_clip = actor.info.clip(Clip::Attack);
+                // the projectile is fired by the attack clip if it has a projectile event
+                let deferred = attack_clip.has_event("projectile");
+                if !attack_clip.frames.is_empty() {
+                    actor.play(Clip::Attack);
+                }
+                if weapon_info.melee {
+                    actor.melee = MeleeState::WindUp { timer: Timer::start(weapon_info.windup) };
+                } else if !deferred {
+                    let handle = actor.handle;
+                    fire_projectile(c, handle);
                 }
             }
         }
     }
 }
 
-fn rand_f32_0_1() -> f32 {
-    let v = macroquad::rand::rand() as f32;
-    v / u32::MAX as f32
+/// Spawns the projectile of the weapon of the actor at its muzzle, emitting a `ShakeEvent` for the weapon
+fn fire_projectile(c:&mut Context, handle:ActorHandle) {
+    let (state, md) = (&mut c.state, &c.metadata);
+    let Some(actor) = state.actor(handle) else { return; };
+    if actor.weapon.shake > 0.0 {
+        c.shake_events.push(ShakeEvent { actor: handle, trauma: actor.weapon.shake });
+    }
+    let Some(projectile_actor_info) = md.actors.get(&actor.weapon.projectile) else { return; };
+    let speed = projectile_actor_info.velocity;
+    let spawn_pos = actor.muzzle_pos();
+    let spread = state.rng.f32_1_1() * actor.weapon.spread;
+    let facing_with_spread = actor.facing + spread;
+    let d = Vec2::new(facing_with_spread.cos(), facing_with_spread.sin());
+    let v = d * speed;
+    let bullet = state.spawn_actor(projectile_actor_info.clone());
+    bullet.owner = handle;
+    bullet.pos = spawn_pos;
+    bullet.vel = v;
+    bullet.facing = facing_with_spread;
+}
+
+/// Progresses melee attacks of actors.
+/// When the wind-up is done, every shootable actor within the cone in front of the hand is hit.
+fn melee(c:&mut Context) {
+    let dt = c.dt;
+    let mut hits = Vec::new();
+    for actor_handle in c.state.actor_handles() {
+        let Some(actor) = c.state.actor(actor_handle) else { continue; };
+        if !actor.is_alive() || actor.is_stunned() {
+            let actor = c.state.actor_mut(actor_handle).unwrap();
+            actor.melee = MeleeState::Idle;
+            continue;
+        }
+        let weapon_info = actor.weapon.clone();
+        let hand = actor.hand_pos();
+        let facing = actor.facing_vector();
+        let actor = c.state.actor_mut(actor_handle).unwrap();
+        match &mut actor.melee {
+            MeleeState::Idle => {},
+            MeleeState::WindUp { timer } => {
+                if !timer.tick(dt) {
+                    continue;
+                }
+                actor.melee = MeleeState::Recovery { timer: Timer::start(weapon_info.recovery) };
+                let attacker = c.state.actor(actor_handle).unwrap();
+                for other_handle in c.state.actor_handles() {
+                    let other = c.state.actor(other_handle).unwrap();
+                    if other_handle == actor_handle || !other.is_alive() || !other.info.shootable || attacker.is_ally(other) {
+                        continue;
+                    }
+                    let v = other.pos - hand;
+                    if v.length() > weapon_info.melee_reach + other.info.radius {
+                        continue;
+                    }
+                    if v.length() > 0.0 && facing.angle_between(v).abs() > weapon_info.melee_arc / 2.0 {
+                        continue;
+                    }
+                    let (min_dmg, max_dmg) = (weapon_info.damage[0], weapon_info.damage[1]);
+                    let dmg = min_dmg + (max_dmg - min_dmg) * c.state.rng.f32_0_1();
+                    let impulse = facing * weapon_info.knockback;
+                    hits.push((actor_handle, other_handle, dmg.floor(), weapon_info.effects.clone(), impulse));
+                }
+            },
+            MeleeState::Recovery { timer } => {
+                if timer.tick(dt) {
+                    actor.melee = MeleeState::Idle;
+                }
+            },
+        }
+    }
+
+    for (attacker, actor_handle, dmg, effects, impulse) in hits.drain(..) {
+        hit(&mut c.state, attacker, actor_handle, dmg, &effects, impulse);
+    }
 }
 
-fn rand_f32_1_1() -> f32 {
-    let v = rand_f32_0_1();
-    let v = v - 0.5;
-    v * 2.0
+/// Damages the actor, pushes it by the impulse and applies the effects
+fn hit(state:&mut State, attacker:ActorHandle, actor_handle:ActorHandle, dmg:f32, effects:&[Rc<EffectInfo>], impulse:Vec2) {
+    state.damage(actor_handle, attacker, dmg);
+    let Some(actor) = state.actor_mut(actor_handle) else { return; };
+    let mass = actor.info.mass;
+    if mass > 0.0 {
+        actor.vel += impulse / mass;
+    }
+    let et = actor.pain_timer.end_time;
+    actor.pain_timer.restart(et);
+    if actor.is_alive() {
+        for effect in effects.iter() {
+            actor.apply_effect(effect);
+        }
+    }
 }
 
 /// updates the game_state struct with the current state of the game and
 /// ensures transition to other states
 pub fn game_state(c:&mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
     match &mut c.state.game_state {
         crate::GameState::Countdown { timer } => {
             timer.tick(dt);
@@ -442,7 +594,7 @@ pub fn game_state(c:&mut Context) {
         crate::GameState::Spawning { mobs_left_to_spawn, mobs_total: _ } => {
             if *mobs_left_to_spawn > 0 {
                 *mobs_left_to_spawn -= 1;
-                let r = macroquad::rand::rand() / 365;
+                let r = c.state.rng.next_u32() / 365;
                 let r = r as f32;
                 let r = r / 365.0;
                 let x = r.cos();
@@ -458,8 +610,7 @@ pub fn game_state(c:&mut Context) {
             if c.state.mobs_left() == 0 {
                 c.state.game_state = GameState::Countdown { timer: Timer::start(5.0) };
             }
-            let Some(player) = c.state.actor(c.state.me) else { return };
-            if player.is_alive() == false {
+            if !c.state.players_alive() {
                 c.state.game_state = GameState::WaitForReadyToRespawn { timer: Timer::start(1.0) }
             }
         },
@@ -470,7 +621,7 @@ pub fn game_state(c:&mut Context) {
             }
         },
         crate::GameState::ReadyToRespawn => {
-            if is_key_pressed(KeyCode::Space) || is_mouse_button_pressed(MouseButton::Left) {
+            if c.player_inputs.iter().any(|x| x.restart) {
                 start(c);
             }
         }
@@ -478,6 +629,10 @@ pub fn game_state(c:&mut Context) {
 }
 
 /// Handle missile actors whom are part of `ContactEvent`.
+///
+/// Missiles damage shootable actors at most once and pierce through up to `pierce_count` of them,
+/// and bounce off other solid actors up to `bounce_count` times before being despawned. Allies of
+/// the owner of the missile are passed through.
 pub fn missile_contact(c:&mut Context) {
     let contacts = c.state.contact_events.clone();
     let mut hits = Vec::new();
@@ -485,37 +640,165 @@ pub fn missile_contact(c:&mut Context) {
         match ev {
             ContactEvent::Actor { actor, other_actor } => {
                 let Some(actor) = c.state.actor(*actor) else { continue;};
-                if actor.info.missile {
-                    let Some(other_actor) = c.state.actor(*other_actor) else { continue;};
-                    if other_actor.info.shootable {
-                        let min_dmg: f32 = actor.info.missile_direct_damage.0;
-                        let max_dmg: f32 = actor.info.missile_direct_damage.1;
-                        let dmg = min_dmg + (max_dmg - min_dmg) * rand_f32_0_1();
-                        let dmg = dmg.floor();
-                        hits.push((other_actor.handle, dmg));
-                    }
-                    
-                    let pos = actor.pos;
-                    c.state.despawn_actor(actor.handle);
-
-                    let max = 8;
-                    for i in 0..max {
-                        let a = i as f32 / max as f32 * PI * 2.0;
-                        let v = Vec2::new(a.cos(), a.sin()) * 2.0;
-                        let spatter = c.state.spawn_actor(c.metadata.actors.get("spatter").unwrap().clone());
-                        spatter.pos = pos;
-                        spatter.vel = v;
+                if !actor.info.missile {
+                    continue;
+                }
+                let Some(other_actor) = c.state.actor(*other_actor) else { continue;};
+                if actor.hit_actors.contains(&other_actor.handle) {
+                    continue;
+                }
+                // missiles pass through allies of their owner
+                if c.state.actor(actor.owner).is_some_and(|x| x.is_ally(other_actor)) {
+                    continue;
+                }
+                let actor_handle = actor.handle;
+                let other_actor_handle = other_actor.handle;
+                let pos = actor.pos;
+                let despawn;
+                if other_actor.info.shootable {
+                    let min_dmg: f32 = actor.info.missile_direct_damage.0;
+                    let max_dmg: f32 = actor.info.missile_direct_damage.1;
+                    let dmg = min_dmg + (max_dmg - min_dmg) * c.state.rng.f32_0_1();
+                    let dmg = dmg * actor.info.pierce_falloff.powi(actor.pierced as i32);
+                    let dmg = dmg.floor();
+                    let mut effects = actor.info.effects.clone();
+                    let mut knockback = actor.info.knockback;
+                    if let Some(owner) = c.state.actor(actor.owner) {
+                        effects.extend(owner.weapon.effects.iter().cloned());
+                        knockback += owner.weapon.knockback;
                     }
+                    let impulse = actor.vel.normalize_or_zero() * knockback;
+                    hits.push((actor.owner, other_actor_handle, dmg, effects, impulse));
+
+                    despawn = actor.pierced >= actor.info.pierce_count;
+                    let actor = c.state.actor_mut(actor_handle).unwrap();
+                    actor.hit_actors.push(other_actor_handle);
+                    actor.pierced += 1;
+                } else if actor.bounced < actor.info.bounce_count {
+                    let normal = (actor.pos - other_actor.pos).normalize_or_zero();
+                    let actor = c.state.actor_mut(actor_handle).unwrap();
+                    actor.bounce(normal);
+                    continue;
+                } else {
+                    despawn = true;
+                }
+
+                if despawn {
+                    c.state.despawn_actor(actor_handle);
+                }
+
+                let max = 8;
+                for i in 0..max {
+                    let a = i as f32 / max as f32 * PI * 2.0;
+                    let v = Vec2::new(a.cos(), a.sin()) * 2.0;
+                    let spatter = c.state.spawn_actor(c.metadata.actors.get("spatter").unwrap().clone());
+                    spatter.pos = pos;
+                    spatter.vel = v;
                 }
             },
         }
     }
 
-    for (actor_handle, dmg) in hits.drain(..) {
-        let Some(actor) = c.state.actor_mut(actor_handle) else { continue;};
-        actor.health -= dmg;
-        let et = actor.pain_timer.end_time;
-        actor.pain_timer.restart(et);
+    for (attacker, actor_handle, dmg, effects, impulse) in hits.drain(..) {
+        hit(&mut c.state, attacker, actor_handle, dmg, &effects, impulse);
+    }
+}
+
+/// Updates the status effects of actors.
+/// Applies damage over time and removes effects whose duration has run out.
+fn status_effects(c:&mut Context) {
+    let dt = c.dt;
+    for actor_handle in c.state.actor_handles() {
+        let Some(actor) = c.state.actor_mut(actor_handle) else { continue; };
+        if actor.effects.is_empty() {
+            continue;
+        }
+        if !actor.is_alive() {
+            actor.effects.clear();
+            continue;
+        }
+        let mut dmg = 0.0;
+        for effect in actor.effects.iter_mut() {
+            effect.timer.tick(dt);
+            dmg += effect.info.damage_per_second * effect.stacks as f32 * dt;
+        }
+        actor.effects.retain(|x| !x.timer.is_done());
+        c.state.damage(actor_handle, ActorHandle::default(), dmg);
+    }
+}
+
+/// Handles `DeathEvent` of actors killed since the last tick.
+/// Players score a point for every actor they kill which is not an ally.
+/// Actors killed with an overkill of at least their `gib_threshold` are despawned and replaced by gibs.
+fn death(c:&mut Context) {
+    let events = std::mem::take(&mut c.state.death_events);
+    for ev in events.iter() {
+        let Some(actor) = c.state.actor(ev.actor) else { continue; };
+        let info = actor.info.clone();
+        let pos = actor.pos;
+        let vel = actor.vel;
+        let scorer = c.state.players.iter()
+            .position(|x| *x == ev.killer)
+            .filter(|_| c.state.actor(ev.killer).is_some_and(|x| !x.is_ally(actor)));
+        if let Some(player) = scorer {
+            c.state.scores[player] += 1;
+        }
+        if info.gib_threshold <= 0.0 || ev.overkill < info.gib_threshold {
+            continue;
+        }
+        let Some(gib_info) = c.metadata.actors.get(&info.gibs) else { continue; };
+        if !c.state.is_player(ev.actor) {
+            c.state.despawn_actor(ev.actor);
+        }
+        for _ in 0..info.gib_count {
+            let a = c.state.rng.f32_0_1() * PI * 2.0;
+            let speed = gib_info.velocity * (0.5 + c.state.rng.f32_0_1());
+            let gib = c.state.spawn_actor(gib_info.clone());
+            gib.pos = pos;
+            gib.vel = vel + Vec2::new(a.cos(), a.sin()) * speed;
+            gib.facing = a;
+        }
+    }
+}
+
+/// Maximum number of corpses kept around, the oldest are despawned first
+const MAX_CORPSES: usize = 64;
+
+/// Updates dead actors.
+/// Corpses fade out and are despawned when they have been dead for `corpse_lifetime` (unless it is zero).
+/// Despawns the oldest corpses when there are more than `MAX_CORPSES`, corpses of players are kept.
+fn corpses(c:&mut Context) {
+    let dt = c.dt;
+    let players = c.state.players.clone();
+    let mut corpses = Vec::new();
+    for actor_handle in c.state.actor_handles() {
+        let Some(actor) = c.state.actor_mut(actor_handle) else { continue; };
+        if actor.is_alive() || actor.info.particle || actor.info.missile {
+            continue;
+        }
+        actor.dead_time += dt;
+        let lifetime = actor.info.corpse_lifetime;
+        if lifetime > 0.0 {
+            let fade = actor.info.corpse_fade;
+            let left = lifetime - actor.dead_time;
+            if left <= 0.0 {
+                c.state.despawn_actor(actor_handle);
+                continue;
+            }
+            if fade > 0.0 && left < fade {
+                actor.color.w = left / fade;
+            }
+        }
+        if !players.contains(&actor_handle) {
+            corpses.push((actor_handle, actor.dead_time));
+        }
+    }
+
+    if corpses.len() > MAX_CORPSES {
+        corpses.sort_by(|a, b| b.1.total_cmp(&a.1));
+        for (actor_handle, _) in corpses.drain(..corpses.len() - MAX_CORPSES) {
+            c.state.despawn_actor(actor_handle);
+        }
     }
 }
 
@@ -523,7 +806,7 @@ pub fn missile_contact(c:&mut Context) {
 /// These are despawned when their health is reduced to zero. 
 /// Their alpha color is reduced to zero over time.
 fn particle(c:&mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
     for actor_handle in c.state.actor_handles() {
         let Some(actor) = c.state.actor_mut(actor_handle) else { continue; };
         if actor.info.particle {
@@ -540,7 +823,7 @@ fn particle(c:&mut Context) {
 /// Updates the pain timer of actors.
 /// Paints the actor redish based upon the timer value.
 fn pain_timer(c:&mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
     for actor_handle in c.state.actor_handles() {
         let Some(actor) = c.state.actor_mut(actor_handle) else { continue; }; {
             actor.pain_timer.tick(dt);
@@ -556,35 +839,239 @@ fn pain_timer(c:&mut Context) {
     }
 }
 
-/// Updates the frame value of actors.
-/// Loops through frames.
+/// Switches actors to the clip they should be playing and advances the playback.
+/// Emits the events of the frames which were reached.
 fn animation(c:&mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
+    c.state.animation_events.clear();
     for actor_handle in c.state.actor_handles() {
         let Some(actor) = c.state.actor_mut(actor_handle) else { continue; };
-        actor.frame += 10.0 * dt;
+        let clip = actor.wanted_clip();
+        if clip != actor.animation.clip {
+            actor.play(clip);
+        }
+        let from = match actor.animation.started {
+            true => None,
+            false => Some(actor.animation.time),
+        };
+        if !actor.animation.started {
+            actor.animation.time += dt;
+        }
+        actor.animation.started = false;
+        let info = actor.info.clone();
+        let events = info.clip(actor.animation.clip).events(from, actor.animation.time);
+        for event in events {
+            c.state.animation_events.push(AnimationEvent { actor: actor_handle, event: event.to_owned() });
+        }
+    }
+}
+
+/// Handles the events of the animation frames reached this tick.
+/// `projectile` fires the weapon of the actor.
+fn frame_events(c:&mut Context) {
+    let events = std::mem::take(&mut c.state.animation_events);
+    for event in events.iter() {
+        let alive = c.state.actor(event.actor).map(|x| x.is_alive()).unwrap_or_default();
+        if event.event == "projectile" && alive {
+            fire_projectile(c, event.actor);
+        }
     }
+    c.state.animation_events = events;
 }
 
 /// Persist and Restore `StateSnapshot` to disk. 
 fn snapshot(c:&mut Context) {
     if is_key_pressed(KeyCode::F5) {
-        let snapshot = StateSnapshot::create_snapshot(&c.state, &c.metadata);
-        let bytes = bincode::serialize(&snapshot).unwrap();
-        std::fs::File::create("quicksave.sav").unwrap().write_all(&bytes).unwrap();
+        save_game(c, "quicksave");
     }
     else if is_key_pressed(KeyCode::F6) {
-        let Ok(mut file) = std::fs::File::open("quicksave.sav") else { return };
-        let mut buf = Vec::new();
-        let Ok(_size) = file.read_to_end(&mut buf) else { return };
-        let snapshot:StateSnapshot = bincode::deserialize(&buf).unwrap();
-        c.state = snapshot.load_snapshot(&c.metadata);
+        load_game(c, "quicksave");
+    }
+}
+
+/// Short description of the state shown in the save menu
+fn save_summary(state: &State) -> String {
+    let alive = state.players.iter().filter(|x| state.actor(**x).map(|x| x.is_alive()).unwrap_or_default()).count();
+    format!("{}/{} players alive, {} zombies left", alive, state.players.len(), state.mobs_left())
+}
+
+fn save_game(c:&mut Context, name: &str) {
+    let snapshot = StateSnapshot::create_snapshot(&c.state, &c.metadata);
+    let info = SaveInfo::new(&snapshot, c.playtime, save_summary(&c.state));
+    if let Err(e) = write_slot(name, &info, &snapshot) {
+        eprintln!("failed to save {}: {}", name, e);
+    }
+}
+
+fn load_game(c:&mut Context, name: &str) -> bool {
+    match read_slot(name) {
+        Ok(save) => load_save(c, name, save),
+        Err(e) => {
+            eprintln!("failed to load {}: {}", name, e);
+            false
+        }
+    }
+}
+
+/// Replaces the state with the state of the save, unless networked as the players of the save may
+/// not match the connected players
+fn load_save(c:&mut Context, name: &str, save: SaveFile) -> bool {
+    if c.net.is_some() {
+        eprintln!("not loading {} while networked", name);
+        return false;
+    }
+    let (state, warnings) = save.snapshot.load_snapshot(&c.metadata);
+    for warning in warnings.iter() {
+        eprintln!("warning: {}: {}", name, warning);
+    }
+    c.state = state;
+    c.playtime = save.info.playtime;
+    c.save_menu.autosaved_round = Some(c.state.round);
+    true
+}
+
+/// Saves to the autosave slot when a countdown to the next round starts
+fn autosave(c:&mut Context) {
+    let GameState::Countdown { .. } = c.state.game_state else { return };
+    if c.state.round == 0 || c.save_menu.autosaved_round == Some(c.state.round) {
+        return;
+    }
+    c.save_menu.autosaved_round = Some(c.state.round);
+    save_game(c, "autosave");
+}
+
+/// Opens the save menu with Escape. Up and down select a save, enter loads it and delete deletes it.
+/// The first row creates a new save with a typed name.
+fn save_menu(c:&mut Context) {
+    let menu = &mut c.save_menu;
+    if let Some(name) = &mut menu.naming {
+        while let Some(ch) = get_char_pressed() {
+            if ch.is_alphanumeric() || ch == ' ' || ch == '-' || ch == '_' {
+                name.push(ch);
+            }
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            name.pop();
+        }
+        if is_key_pressed(KeyCode::Escape) {
+            menu.naming = None;
+        } else if is_key_pressed(KeyCode::Enter) && !name.is_empty() {
+            let name = name.clone();
+            c.save_menu.naming = None;
+            save_game(c, &name);
+            c.save_menu.slots = list_slots();
+        }
+        return;
+    }
+    if is_key_pressed(KeyCode::Escape) {
+        menu.open = !menu.open;
+        menu.slots = list_slots();
+        menu.selected = 0;
+    }
+    if !menu.open {
+        return;
     }
+    if is_key_pressed(KeyCode::Up) {
+        menu.selected = menu.selected.saturating_sub(1);
+    }
+    if is_key_pressed(KeyCode::Down) {
+        menu.selected = (menu.selected + 1).min(menu.slots.len());
+    }
+    let Some(slot) = menu.selected.checked_sub(1).and_then(|x| menu.slots.get(x)) else {
+        if is_key_pressed(KeyCode::Enter) {
+            // clear the characters typed while browsing
+            while get_char_pressed().is_some() {}
+            menu.naming = Some(format!("save {}", menu.slots.len() + 1));
+        }
+        return;
+    };
+    let name = slot.name.clone();
+    if is_key_pressed(KeyCode::Enter) && load_game(c, &name) {
+        c.save_menu.open = false;
+    } else if is_key_pressed(KeyCode::Delete) {
+        if let Err(e) = delete_slot(&name) {
+            eprintln!("failed to delete {}: {}", name, e);
+        }
+        c.save_menu.slots = list_slots();
+        c.save_menu.selected = c.save_menu.selected.min(c.save_menu.slots.len());
+    }
+}
+
+fn draw_save_menu(c:&mut Context) {
+    let menu = &c.save_menu;
+    if !menu.open {
+        return;
+    }
+    let list = &mut c.draw_list;
+    list.set_screen();
+    list.rect(0.0, 0.0, list.screen.x, list.screen.y, Color::new(0.0, 0.0, 0.0, 0.75));
+    let font_size = 24.0;
+    let x = font_size * 2.0;
+    let mut y = font_size * 3.0;
+    list.text("SAVES (enter: load, delete: delete, escape: close)", Vec2::new(x, y), font_size, WHITE, Align::Left);
+    y += font_size * 2.0;
+    let new_save = match &menu.naming {
+        Some(name) => format!("New save: {}_", name),
+        None => "New save".to_string(),
+    };
+    let rows = std::iter::once(new_save).chain(menu.slots.iter().map(|slot| {
+        let info = &slot.info;
+        let playtime = info.playtime as u32;
+        format!(
+            "{}  round {}  {}  played {}:{:02}:{:02}  {}",
+            slot.name,
+            info.round,
+            info.date(),
+            playtime / 3600,
+            playtime / 60 % 60,
+            playtime % 60,
+            info.summary
+        )
+    }));
+    for (i, row) in rows.enumerate() {
+        let color = if i == menu.selected { YELLOW } else { WHITE };
+        list.text(&row, Vec2::new(x, y), font_size, color, Align::Left);
+        y += font_size * 1.5;
+    }
+}
+
+/// Reloads the metadata when its files have changed or F9 is pressed, keeping the state of the actors.
+/// Disabled while networked, as every machine must have the same metadata.
+fn hot_reload(c:&mut Context) {
+    if !c.watcher.changed(get_time()) && !is_key_pressed(KeyCode::F9) {
+        return;
+    }
+    if c.net.is_some() {
+        eprintln!("not reloading metadata while networked, restart to apply the changes");
+        return;
+    }
+    let metadata = match Metadata::from_disk(&c.metadata.mods(), true) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("failed to reload metadata:\n{}", e);
+            return;
+        }
+    };
+    for warning in metadata.warnings.iter() {
+        eprintln!("{}", warning);
+    }
+    c.watcher.watch(&metadata);
+    c.state.relink(&metadata);
+    c.metadata = metadata;
+}
+
+/// Reads the inputs and simulates a tick, unless the game is paused by the save menu
+fn play(c:&mut Context) {
+    if c.save_menu.open {
+        return;
+    }
+    inputs(c);
+    step(c);
 }
 
 /// Increment age of actors and despawn the actor if its age reaches max_age (unless max_age is zero)
 fn age(c:&mut Context) {
-    let dt = get_frame_time();
+    let dt = c.dt;
     for actor_handle in c.state.actor_handles() {
         let actor = c.state.actor_mut(actor_handle).unwrap();
         actor.age += dt;
@@ -594,20 +1081,157 @@ fn age(c:&mut Context) {
     }
 }
 
+/// Bounces missiles off the bounds of the game while they have bounces left.
+/// Bouncing off a corner flips both axes and counts as a single bounce.
+fn missile_bounds(c:&mut Context) {
+    let b = c.state.bounds;
+    for actor_handle in c.state.actor_handles() {
+        let Some(actor) = c.state.actor_mut(actor_handle) else { continue; };
+        if !actor.info.missile || actor.bounced >= actor.info.bounce_count {
+            continue;
+        }
+        let (pos, vel) = (actor.pos, actor.vel);
+        let flip_x = (pos.x < b.left && vel.x < 0.0) || (pos.x > b.right() && vel.x > 0.0);
+        let flip_y = (pos.y < b.top && vel.y < 0.0) || (pos.y > b.bottom() && vel.y > 0.0);
+        if !flip_x && !flip_y {
+            continue;
+        }
+        if flip_x {
+            actor.vel.x = -vel.x;
+        }
+        if flip_y {
+            actor.vel.y = -vel.y;
+        }
+        actor.facing = f32::atan2(actor.vel.y, actor.vel.x);
+        actor.bounced += 1;
+    }
+}
+
 /// Ensures players are not able to leave the bounds of the game
 pub fn player_bounds(c:&mut Context) {
     let b = c.state.bounds;
-    if let Some(actor) = c.state.actor_mut(c.state.me){
-        actor.pos = actor.pos.clamp([b.left, b.top].into(), [b.right(), b.bottom()].into());
+    for handle in c.state.players.clone() {
+        if let Some(actor) = c.state.actor_mut(handle) {
+            actor.pos = actor.pos.clamp([b.left, b.top].into(), [b.right(), b.bottom()].into());
+        }
     }
 }
 
 
-/// Clears and starts the game by spawning the player
+/// Spawns the actor of a player next to the actors of the other players
+fn spawn_player(state: &mut State, metadata: &Metadata) {
+    let index = state.players.len();
+    let player = state.spawn_actor(metadata.actors.get("guy").unwrap().clone());
+    player.pos.x = index as f32 * 1.5;
+    let handle = player.handle;
+    state.players.push(handle);
+    state.scores.push(0);
+}
+
+/// Clears and starts the game by spawning an actor for each player
 fn start(c: &mut Context) {
     c.state = State::default();
-    let player = c.state.spawn_actor(c.metadata.actors.get("guy").unwrap().clone());
-    c.state.me = player.handle;
+    for _ in 0..c.inputs.len() {
+        spawn_player(&mut c.state, &c.metadata);
+    }
+}
+
+/// Receives messages from clients when hosting.
+/// Joining clients are given a player which is spawned, and the newest input of each client is kept.
+/// Clients which have not been heard from for `CLIENT_TIMEOUT` are dropped, their players stand still.
+fn net_host(c: &mut Context) {
+    let Some(Net::Server(server)) = &mut c.net else { return };
+    let now = server.socket.now();
+    for (addr, msg) in server.socket.recv() {
+        match msg {
+            NetMessage::Join => {
+                let player = match server.clients.iter_mut().find(|x| x.addr == addr) {
+                    Some(client) => {
+                        client.last_heard = now;
+                        client.player
+                    }
+                    None => {
+                        // players of clients which timed out are taken over
+                        let free = (0..c.inputs.len()).find(|x| matches!(c.inputs[*x], InputSource::Network) && server.client(*x).is_none());
+                        let player = match free {
+                            Some(player) => player,
+                            None => {
+                                c.inputs.push(InputSource::Network);
+                                spawn_player(&mut c.state, &c.metadata);
+                                c.inputs.len() - 1
+                            }
+                        };
+                        server.clients.push(RemoteClient {
+                            addr,
+                            player,
+                            seq: 0,
+                            input: PlayerInput::default(),
+                            tick: 0,
+                            baselines: Default::default(),
+                            last_heard: now,
+                        });
+                        player
+                    }
+                };
+                server.socket.send(addr, &NetMessage::Welcome { player });
+            },
+            NetMessage::Input { seq, tick, input } => {
+                let Some(client) = server.clients.iter_mut().find(|x| x.addr == addr) else { continue };
+                client.last_heard = now;
+                client.receive_input(seq, input);
+                client.tick = client.tick.max(tick);
+            },
+            _ => {}
+        }
+    }
+    for client in server.drop_quiet_clients() {
+        eprintln!("client {} of player {} timed out", client.addr, client.player + 1);
+    }
+}
+
+/// Sends the state of the game to every client when hosting
+fn net_broadcast(c: &mut Context) {
+    let Some(Net::Server(server)) = &mut c.net else { return };
+    if server.clients.is_empty() {
+        return;
+    }
+    let snapshot = StateSnapshot::create_snapshot(&c.state, &c.metadata);
+    if let Err(e) = server.broadcast(&snapshot, c.playtime, &c.metadata) {
+        eprintln!("failed to broadcast the state: {}", e);
+    }
+}
+
+/// Sends the input of the local player to the host and replaces the state with the snapshots received.
+/// Other actors are interpolated between snapshots, while the own actor is predicted from the inputs
+/// not yet acknowledged by the host.
+fn net_client(c: &mut Context) {
+    let Some(Net::Client(client)) = &mut c.net else { return };
+    client.join();
+    for (_, msg) in client.socket.recv() {
+        match msg {
+            NetMessage::Welcome { player } => client.player = Some(player),
+            NetMessage::Snapshot { tick, ack, delta } => client.receive_snapshot(tick, ack, delta, &c.metadata),
+            _ => {}
+        }
+    }
+    let Some(player) = client.player else { return };
+    let input = c.inputs.first().map(|x| x.read(&c.camera, c.state.player_pos(player), &c.gamepads)).unwrap_or_default();
+    let aim = input.aim_dir();
+    client.send_input(input, c.dt);
+
+    let Some(snapshot) = client.interpolated_snapshot() else { return };
+    c.state = snapshot.load_snapshot(&c.metadata).0;
+
+    let Some((_, _, latest)) = client.snapshots.back() else { return };
+    let Some(own) = latest.players.get(player).and_then(|x| latest.actors.get(*x)) else { return };
+    let Some(handle) = c.state.players.get(player).copied() else { return };
+    let Some(actor) = c.state.actor_mut(handle) else { return };
+    if actor.is_alive() {
+        actor.pos = own.state.pos + client.predicted_offset(actor.info.speed);
+        if let Some(v) = aim {
+            actor.facing = f32::atan2(v.y, v.x);
+        }
+    }
 }
 
 pub fn once(c: &mut Context) {
@@ -617,28 +1241,589 @@ pub fn once(c: &mut Context) {
     }
 }
 
+/// Draws the state without advancing it, such as for screenshots, with or without the HUD
+pub fn draw_state(c: &mut Context, hud: bool) {
+    let systems = [camera, draw, draw_bounds, draw_debug];
+    for system in systems.iter() {
+        system(c);
+    }
+    if hud {
+        draw_hud(c);
+    }
+}
+
+/// Simulates the ticks which are due when playing peer-to-peer, rolling back when needed
+fn net_peer(c: &mut Context) {
+    let Some(Net::Peer(mut peer)) = c.net.take() else { return };
+    let input = c.inputs.get(peer.player).map(|x| x.read(&c.camera, c.state.player_pos(peer.player), &c.gamepads)).unwrap_or_default();
+    peer.update(c, input);
+    c.net = Some(Net::Peer(peer));
+}
+
+/// Systems which advance the simulation.
+///
+/// Given the same `State`, `Context::player_inputs` and `Context::dt` these produce the same result,
+/// such that ticks can be re-simulated.
+const SIMULATION: [fn(&mut Context); 18] = [
+    game_state,
+    player,
+    bots,
+    attack,
+    melee,
+    locomotion,
+    physics,
+    player_bounds,
+    missile_contact,
+    missile_bounds,
+    status_effects,
+    death,
+    corpses,
+    particle,
+    pain_timer,
+    animation,
+    frame_events,
+    age,
+];
+
+/// Advances the simulation by a single tick
+pub fn simulate(c: &mut Context) {
+    for system in SIMULATION.iter() {
+        system(c);
+    }
+}
+
+/// Simulates a tick and records it to the trace, if any
+fn step(c: &mut Context) {
+    simulate(c);
+    c.playtime += c.dt;
+    if let Some(trace) = c.trace.as_mut() {
+        let snapshot = StateSnapshot::create_snapshot(&c.state, &c.metadata);
+        trace.record(c.dt, &c.player_inputs, snapshot);
+    }
+}
+
+/// Simulates the next tick of the replay using its recorded inputs
+fn replay(c: &mut Context) {
+    let Some(frame) = c.replay.as_mut().and_then(|x| x.frames.pop_front()) else {
+        return;
+    };
+    c.dt = frame.dt;
+    c.player_inputs = frame.inputs;
+    step(c);
+}
+
 pub fn tick(c: &mut Context) {
+    c.dt = get_frame_time();
+    c.gamepads.update();
+    if let Some(Net::Client(_)) = c.net {
+        let systems = [
+            net_client,
+            camera,
+            draw,
+            draw_bounds,
+            draw_debug,
+            draw_hud,
+        ];
+        for system in systems.iter() {
+            system(c);
+        }
+        return;
+    }
+    if let Some(Net::Peer(_)) = c.net {
+        let systems = [
+            camera,
+            net_peer,
+            draw,
+            draw_bounds,
+            draw_debug,
+            draw_hud,
+        ];
+        for system in systems.iter() {
+            system(c);
+        }
+        return;
+    }
+
+    if c.replay.is_some() {
+        let systems = [
+            camera,
+            replay,
+            draw,
+            draw_bounds,
+            draw_debug,
+            draw_hud,
+        ];
+        for system in systems.iter() {
+            system(c);
+        }
+        return;
+    }
     let systems = [
-        game_state,
+        net_host,
+        hot_reload,
         camera,
-        player,
-        bots,
-        attack,
-        locomotion,
-        physics,
-        player_bounds,
-        missile_contact,
-        particle,
-        pain_timer,
-        animation,
-        age,
+        save_menu,
+        play,
         draw,
         draw_bounds,
         draw_debug,
         draw_hud,
-        snapshot
+        draw_save_menu,
+        snapshot,
+        autosave,
+        net_broadcast
     ];
     for system in systems.iter() {
         system(c);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{system_clock, Client, CLIENT_TIMEOUT, DrawCommand, NetClock, NetConditions, NetSocket, RecordingRenderer, Renderer, Server};
+
+    fn context() -> Context {
+        Context {
+            metadata: Metadata::from_disk(&[], false).unwrap(),
+            dt: 0.1,
+            ..Default::default()
+        }
+    }
+
+    /// Spawns an actor which is about to finish the wind-up of a melee attack
+    fn spawn_attacker(c: &mut Context, info: &str, pos: Vec2, facing: f32) -> ActorHandle {
+        let info = c.metadata.actors.get(info).unwrap().clone();
+        let actor = c.state.spawn_actor(info);
+        actor.pos = pos;
+        actor.facing = facing;
+        actor.melee = MeleeState::WindUp { timer: Timer::start(0.05) };
+        actor.handle
+    }
+
+    #[test]
+    fn melee_does_not_hit_allies() {
+        let mut c = context();
+        let a = spawn_attacker(&mut c, "zombie", Vec2::new(0.0, 0.0), 0.0);
+        let b = spawn_attacker(&mut c, "zombie", Vec2::new(1.2, 0.0), PI);
+        let guy = c.metadata.actors.get("guy").unwrap().clone();
+        let guy = c.state.spawn_actor(guy);
+        guy.pos = Vec2::new(1.2, 0.4);
+        let guy = guy.handle;
+
+        melee(&mut c);
+
+        for zombie in [a, b] {
+            let zombie = c.state.actor(zombie).unwrap();
+            assert_eq!(zombie.health, zombie.info.health);
+        }
+        let guy = c.state.actor(guy).unwrap();
+        assert!(guy.health < guy.info.health);
+    }
+
+    #[test]
+    fn melee_can_be_dodged_during_the_wind_up() {
+        let mut c = context();
+        let zombie = c.metadata.actors.get("zombie").unwrap().clone();
+        let zombie = c.state.spawn_actor(zombie);
+        zombie.melee = MeleeState::WindUp { timer: Timer::start(zombie.weapon.windup) };
+        let zombie = zombie.handle;
+        spawn_player(&mut c.state, &c.metadata);
+        let guy = c.state.players[0];
+        c.state.actor_mut(guy).unwrap().pos = Vec2::new(1.2, 0.0);
+
+        bots(&mut c);
+        melee(&mut c);
+        // the guy steps out of the cone before the wind-up is done
+        c.state.actor_mut(guy).unwrap().pos = Vec2::new(0.0, 1.2);
+        for _ in 0..5 {
+            bots(&mut c);
+            melee(&mut c);
+        }
+
+        assert!(matches!(c.state.actor(zombie).unwrap().melee, MeleeState::Idle | MeleeState::Recovery { .. }));
+        let guy = c.state.actor(guy).unwrap();
+        assert_eq!(guy.health, guy.info.health);
+    }
+
+    #[test]
+    fn actors_at_nan_positions_do_not_panic() {
+        let mut c = context();
+        spawn_player(&mut c.state, &c.metadata);
+        spawn_player(&mut c.state, &c.metadata);
+        c.state.actor_mut(c.state.players[0]).unwrap().pos = Vec2::NAN;
+        let zombie = c.metadata.actors.get("zombie").unwrap().clone();
+        c.state.spawn_actor(zombie).pos = Vec2::new(3.0, 0.0);
+
+        bots(&mut c);
+        record(&mut c);
+    }
+
+    /// Draws the state without the HUD and returns the commands recorded by a `RecordingRenderer`
+    fn record(c: &mut Context) -> Vec<DrawCommand> {
+        let mut renderer = RecordingRenderer::new(Vec2::new(640.0, 360.0));
+        c.draw_list.begin(renderer.screen_size());
+        draw_state(c, false);
+        renderer.render(&c.draw_list.commands);
+        renderer.frames.pop().unwrap()
+    }
+
+    /// Sprites of the frames of the image, in the order they are drawn
+    fn sprites<'a>(commands: &'a [DrawCommand], image: &str) -> Vec<(&'a Rect, bool, bool)> {
+        commands.iter().filter_map(|x| match x {
+            DrawCommand::Sprite { frame, dest, flip_x, flip_y, .. } if frame.image.name == image => Some((dest, *flip_x, *flip_y)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn actors_facing_left_are_flipped() {
+        let mut c = context();
+        let info = c.metadata.actors.get("guy").unwrap().clone();
+        for (y, facing) in [(0.0, PI), (2.0, 0.0)] {
+            let guy = c.state.spawn_actor(info.clone());
+            guy.pos = Vec2::new(0.0, y);
+            guy.facing = facing;
+        }
+        let commands = record(&mut c);
+
+        let body = info.clip(Clip::Idle).frame(0.0).unwrap().image.name.clone();
+        let flips: Vec<bool> = sprites(&commands, &body).iter().map(|x| x.1).collect();
+        assert_eq!(flips, vec![true, false]);
+        // weapons are rotated to face, so they are flipped vertically to stay upright
+        let weapon = info.weapon.frame(None).unwrap().image.name.clone();
+        let flips: Vec<(bool, bool)> = sprites(&commands, &weapon).iter().map(|x| (x.1, x.2)).collect();
+        assert_eq!(flips, vec![(false, true), (false, false)]);
+    }
+
+    #[test]
+    fn actors_are_drawn_from_top_to_bottom() {
+        let mut c = context();
+        let info = c.metadata.actors.get("zombie").unwrap().clone();
+        for y in [2.0, -1.0, 0.5] {
+            c.state.spawn_actor(info.clone()).pos = Vec2::new(0.0, y);
+        }
+        let commands = record(&mut c);
+
+        let body = info.clip(Clip::Idle).frame(0.0).unwrap().image.name.clone();
+        let tops: Vec<f32> = sprites(&commands, &body).iter().map(|x| x.0.y).collect();
+        assert_eq!(tops.len(), 3);
+        assert!(tops.windows(2).all(|x| x[0] < x[1]), "{:?}", tops);
+    }
+
+    #[test]
+    fn shots_shake_the_camera_of_local_players_once() {
+        let mut c = context();
+        c.inputs = InputSource::local_players(1);
+        c.inputs.push(InputSource::Network);
+        spawn_player(&mut c.state, &c.metadata);
+        spawn_player(&mut c.state, &c.metadata);
+        let fire = PlayerInput { attack_dir: Vec2::X, weapon: Some(1), ..Default::default() };
+        let mut rollback = crate::Rollback::new();
+        rollback.advance(&mut c, vec![fire.clone(), fire]);
+        assert_eq!(c.shake_events.len(), 2);
+
+        rollback.resimulate(&mut c, 0);
+        assert_eq!(c.shake_events.len(), 2);
+
+        c.dt = 0.0;
+        c.draw_list.begin(Vec2::new(640.0, 360.0));
+        camera(&mut c);
+        assert!(c.shake_events.is_empty());
+        // only the shot of the local player shakes the camera
+        assert_eq!(c.camera_rig.trauma, c.metadata.weapons.get("pistol").unwrap().shake);
+    }
+
+    #[test]
+    fn missiles_pass_through_allies_of_their_owner() {
+        let mut c = context();
+        spawn_player(&mut c.state, &c.metadata);
+        spawn_player(&mut c.state, &c.metadata);
+        let (a, b) = (c.state.players[0], c.state.players[1]);
+        let info = c.metadata.actors.get("rifle_bullet").unwrap().clone();
+        let bullet = c.state.spawn_actor(info);
+        bullet.owner = a;
+        let bullet = bullet.handle;
+        c.state.contact_events.push(ContactEvent::Actor { actor: bullet, other_actor: b });
+
+        missile_contact(&mut c);
+
+        let b = c.state.actor(b).unwrap();
+        assert_eq!(b.health, b.info.health);
+        let bullet = c.state.actor(bullet).unwrap();
+        assert_eq!(bullet.pierced, 0);
+        assert!(bullet.hit_actors.is_empty());
+    }
+
+    /// Spawns a bullet which bounces once
+    fn spawn_bouncing_bullet(c: &mut Context, pos: Vec2, vel: Vec2) -> ActorHandle {
+        let info = c.metadata.actors.get("bullet").unwrap();
+        let info = Rc::new(crate::ActorInfo { bounce_count: 1, ..(**info).clone() });
+        let bullet = c.state.spawn_actor(info);
+        bullet.pos = pos;
+        bullet.vel = vel;
+        bullet.handle
+    }
+
+    #[test]
+    fn missiles_bounce_off_the_bounds() {
+        let mut c = context();
+        c.state.bounds = crate::state::Rect { left: -10.0, top: -10.0, width: 20.0, height: 20.0 };
+        let corner = spawn_bouncing_bullet(&mut c, Vec2::new(-10.5, -10.5), Vec2::new(-3.0, -1.0));
+        let side = spawn_bouncing_bullet(&mut c, Vec2::new(10.5, 0.0), Vec2::new(3.0, -1.0));
+
+        missile_bounds(&mut c);
+
+        let corner = c.state.actor(corner).unwrap();
+        assert_eq!((corner.vel, corner.bounced), (Vec2::new(3.0, 1.0), 1));
+        let side = c.state.actor(side).unwrap();
+        assert_eq!((side.vel, side.bounced), (Vec2::new(-3.0, -1.0), 1));
+
+        // without bounces left missiles leave the bounds
+        let side = side.handle;
+        c.state.actor_mut(side).unwrap().vel = Vec2::new(3.0, 0.0);
+        missile_bounds(&mut c);
+        assert_eq!(c.state.actor(side).unwrap().vel, Vec2::new(3.0, 0.0));
+    }
+
+    #[test]
+    fn missiles_bounce_off_solid_actors() {
+        let mut c = context();
+        let zombie = c.metadata.actors.get("zombie").unwrap();
+        let wall = Rc::new(crate::ActorInfo { shootable: false, ..(**zombie).clone() });
+        let wall = c.state.spawn_actor(wall);
+        wall.pos = Vec2::new(1.0, 0.0);
+        let wall = wall.handle;
+        let bullet = spawn_bouncing_bullet(&mut c, Vec2::ZERO, Vec2::new(10.0, 0.0));
+        c.state.contact_events.push(ContactEvent::Actor { actor: bullet, other_actor: wall });
+
+        missile_contact(&mut c);
+
+        let actor = c.state.actor(bullet).unwrap();
+        assert_eq!((actor.vel, actor.bounced), (Vec2::new(-10.0, 0.0), 1));
+        // the missile is despawned when it hits a solid actor without bounces left
+        missile_contact(&mut c);
+        assert!(c.state.actor(bullet).is_none());
+    }
+
+    #[test]
+    fn effects_follow_their_stacking_rules() {
+        let mut c = context();
+        let zombie = c.metadata.actors.get("zombie").unwrap().clone();
+        let zombie = c.state.spawn_actor(zombie).handle;
+        let effect = |c: &Context, name: &str| c.state.actor(zombie).unwrap().effects.iter().find(|x| x.info.name == name).cloned().unwrap();
+        let names = ["slowed", "burning", "stunned"];
+        let infos: Vec<_> = names.iter().map(|x| c.metadata.effects.get(*x).unwrap().clone()).collect();
+        assert!(matches!(infos[0].stacking, crate::Stacking::Refresh));
+        assert!(matches!(infos[1].stacking, crate::Stacking::Stack));
+        assert!(matches!(infos[2].stacking, crate::Stacking::Ignore));
+        for info in infos.iter() {
+            c.state.actor_mut(zombie).unwrap().apply_effect(info);
+        }
+        status_effects(&mut c);
+
+        for _ in 0..infos[1].max_stacks + 1 {
+            for info in infos.iter() {
+                c.state.actor_mut(zombie).unwrap().apply_effect(info);
+            }
+        }
+
+        let slowed = effect(&c, "slowed");
+        assert_eq!((slowed.stacks, slowed.timer.timer), (1, 0.0));
+        let burning = effect(&c, "burning");
+        assert_eq!((burning.stacks, burning.timer.timer), (infos[1].max_stacks, 0.0));
+        let stunned = effect(&c, "stunned");
+        assert_eq!((stunned.stacks, stunned.timer.timer), (1, c.dt));
+        assert_eq!(c.state.actor(zombie).unwrap().effects.len(), 3);
+    }
+
+    #[test]
+    fn burning_bullets_deal_damage_over_time() {
+        let mut c = context();
+        spawn_player(&mut c.state, &c.metadata);
+        let player = c.state.players[0];
+        c.state.actor_mut(player).unwrap().weapon = c.metadata.weapons.get("machinegun").unwrap().clone();
+        let zombie = c.metadata.actors.get("zombie").unwrap().clone();
+        let zombie = c.state.spawn_actor(zombie);
+        // survives the bullets and the burning
+        zombie.health = 100.0;
+        let zombie = zombie.handle;
+        for _ in 0..2 {
+            let bullet = c.state.spawn_actor(c.metadata.actors.get("bullet").unwrap().clone());
+            bullet.owner = player;
+            let bullet = bullet.handle;
+            c.state.contact_events.push(ContactEvent::Actor { actor: bullet, other_actor: zombie });
+        }
+        missile_contact(&mut c);
+        let actor = c.state.actor(zombie).unwrap();
+        assert_eq!(actor.effects.len(), 1);
+        assert_eq!(actor.effects[0].stacks, 2);
+        let health = actor.health;
+
+        for _ in 0..10 {
+            status_effects(&mut c);
+        }
+
+        let burning = c.metadata.effects.get("burning").unwrap();
+        let expected = health - burning.damage_per_second * 2.0 * 1.0;
+        assert!((c.state.actor(zombie).unwrap().health - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn players_score_kills_of_enemies() {
+        let mut c = context();
+        spawn_player(&mut c.state, &c.metadata);
+        spawn_player(&mut c.state, &c.metadata);
+        let (a, b) = (c.state.players[0], c.state.players[1]);
+        let zombie = c.metadata.actors.get("zombie").unwrap().clone();
+        let zombie = c.state.spawn_actor(zombie).handle;
+
+        c.state.damage(zombie, a, 1000.0);
+        c.state.damage(b, a, 1000.0);
+        death(&mut c);
+
+        assert_eq!(c.state.scores, vec![1, 0]);
+    }
+
+    #[test]
+    fn overkill_hits_gib_their_target() {
+        let mut c = context();
+        spawn_player(&mut c.state, &c.metadata);
+        let player = c.state.players[0];
+        let info = c.metadata.actors.get("zombie").unwrap().clone();
+        let (gibbed, killed) = (c.state.spawn_actor(info.clone()).handle, c.state.spawn_actor(info.clone()).handle);
+        c.state.actor_mut(killed).unwrap().health = 5.0;
+
+        // the strongest rifle bullet gibs a zombie, the last bullet of a pistol does not
+        let rifle_bullet = c.metadata.actors.get("rifle_bullet").unwrap().missile_direct_damage.1;
+        c.state.damage(gibbed, player, rifle_bullet);
+        let bullet = c.metadata.actors.get("bullet").unwrap().missile_direct_damage.1;
+        c.state.damage(killed, player, bullet);
+        death(&mut c);
+
+        assert!(c.state.actor(gibbed).is_none());
+        assert!(c.state.actor(killed).is_some());
+        let gibs = c.state.actor_handles().into_iter().filter(|x| c.state.actor(*x).unwrap().info.name == info.gibs).count();
+        assert_eq!(gibs, info.gib_count as usize);
+        assert_eq!(c.state.scores, vec![2]);
+    }
+
+    #[test]
+    fn oldest_corpses_over_the_cap_are_despawned() {
+        let mut c = context();
+        spawn_player(&mut c.state, &c.metadata);
+        let player = c.state.players[0];
+        c.state.actor_mut(player).unwrap().health = 0.0;
+        c.state.actor_mut(player).unwrap().dead_time = 100.0;
+        // the cap is shared by every kind of actor
+        let dead: Vec<_> = (0..MAX_CORPSES + 3).map(|i| {
+            let info = ["zombie", "guy"][i % 2];
+            let actor = c.state.spawn_actor(c.metadata.actors.get(info).unwrap().clone());
+            actor.health = 0.0;
+            actor.dead_time = i as f32 * 0.01;
+            actor.handle
+        }).collect();
+
+        corpses(&mut c);
+
+        assert_eq!(c.state.actor_handles().len(), MAX_CORPSES + 1);
+        // the corpses which have been dead the longest go first, the corpse of the player is kept
+        assert!(dead[MAX_CORPSES..].iter().all(|x| c.state.actor(*x).is_none()));
+        assert!(c.state.actor(player).is_some());
+    }
+
+    #[test]
+    fn saves_are_not_loaded_while_networked() {
+        let (mut host, _, _) = host_and_client();
+        let mut other = context();
+        spawn_player(&mut other.state, &other.metadata);
+        spawn_player(&mut other.state, &other.metadata);
+        let snapshot = StateSnapshot::create_snapshot(&other.state, &other.metadata);
+        let save = || SaveFile { info: SaveInfo::new(&snapshot, 1.0, String::new()), snapshot: snapshot.clone() };
+
+        assert!(!load_save(&mut host, "other", save()));
+        assert!(host.state.players.is_empty());
+        assert!(load_save(&mut context(), "other", save()));
+    }
+
+    /// Host and client contexts talking over 127.0.0.1, with a clock advanced by `step`
+    fn host_and_client() -> (Context, Context, Rc<std::cell::Cell<f64>>) {
+        let time = Rc::new(std::cell::Cell::new(0.0));
+        let clock: NetClock = {
+            let time = time.clone();
+            Rc::new(move || time.get())
+        };
+        let socket = || NetSocket::bind_with("127.0.0.1:0", NetConditions::default(), clock.clone(), Default::default()).unwrap();
+        let server = Server::from_socket(socket());
+        let client = Client::from_socket(server.socket.local_addr().unwrap(), socket());
+        let mut host = Context { net: Some(Net::Server(server)), ..context() };
+        let mut client = Context { net: Some(Net::Client(client)), ..context() };
+        let zombie = host.metadata.actors.get("zombie").unwrap().clone();
+        host.state.spawn_actor(zombie).pos = Vec2::new(3.0, -2.0);
+        client.dt = 0.05;
+        (host, client, time)
+    }
+
+    /// Advances the clock and runs the networking systems of both sides
+    fn step(host: &mut Context, client: &mut Context, time: &std::cell::Cell<f64>) {
+        time.set(time.get() + client.dt as f64);
+        net_client(client);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        net_host(host);
+        net_broadcast(host);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    #[test]
+    fn client_receives_state_of_host() {
+        let (mut host, mut client, time) = host_and_client();
+        for _ in 0..200 {
+            step(&mut host, &mut client, &time);
+            if client.state.actor_handles().len() == 2 {
+                break;
+            }
+        }
+        let Some(Net::Client(net)) = &client.net else { unreachable!() };
+        assert_eq!(net.player, Some(0));
+        assert_eq!(host.inputs.len(), 1);
+        assert_eq!(client.state.players.len(), 1);
+        let positions = |c: &Context| {
+            let mut positions: Vec<_> = c.state.actor_handles().into_iter().map(|x| c.state.actor(x).unwrap().pos.to_array()).collect();
+            positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            positions
+        };
+        assert_eq!(positions(&client), positions(&host));
+    }
+
+    #[test]
+    fn quiet_clients_time_out() {
+        let (mut host, mut client, time) = host_and_client();
+        for _ in 0..200 {
+            step(&mut host, &mut client, &time);
+            if let Some(Net::Server(server)) = &host.net {
+                if !server.clients.is_empty() {
+                    break;
+                }
+            }
+        }
+        time.set(time.get() + CLIENT_TIMEOUT + 1.0);
+        net_host(&mut host);
+        let Some(Net::Server(server)) = &host.net else { unreachable!() };
+        assert!(server.clients.is_empty());
+        let addr = server.socket.local_addr().unwrap();
+
+        // a new client takes over the player of the client which timed out
+        let socket = NetSocket::bind_with("127.0.0.1:0", NetConditions::default(), system_clock(), Default::default()).unwrap();
+        let mut rejoined = Context { net: Some(Net::Client(Client::from_socket(addr, socket))), ..context() };
+        for _ in 0..200 {
+            step(&mut host, &mut rejoined, &time);
+            if let Some(Net::Client(Client { player: Some(_), .. })) = &rejoined.net {
+                break;
+            }
+        }
+        let Some(Net::Client(net)) = &rejoined.net else { unreachable!() };
+        assert_eq!(net.player, Some(0));
+        assert_eq!(host.inputs.len(), 1);
+        assert_eq!(host.state.players.len(), 1);
+    }
+}
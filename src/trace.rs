@@ -0,0 +1,224 @@
+//! Per-tick traces of the simulation, used to find nondeterminism.
+//!
+//! A trace records the inputs of every tick together with a snapshot and checksum of the resulting
+//! `State`. Two traces, for example of a game and its replay or of two network peers, are compared
+//! tick by tick to find the first tick where they diverge and which fields differ.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ActorHandle, ActorSnapshot, ActorState, PlayerInput, StateSnapshot};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TraceFrame {
+    pub tick: u32,
+    /// checksum of `snapshot`
+    pub checksum: u64,
+    /// seconds simulated by the tick
+    pub dt: f32,
+    /// inputs of the players used to simulate the tick
+    pub inputs: Vec<PlayerInput>,
+    /// state after the tick was simulated
+    pub snapshot: StateSnapshot,
+}
+
+/// Appends a `TraceFrame` to a file for every simulated tick
+pub struct TraceWriter {
+    file: BufWriter<File>,
+    /// tick which is recorded next
+    pub tick: u32,
+}
+
+/// Plays back the inputs of a recorded trace instead of reading them from the players
+pub struct Replay {
+    pub frames: VecDeque<TraceFrame>,
+}
+
+/// First tick where two traces differ
+pub struct Divergence {
+    pub tick: u32,
+    /// description of every field which differs
+    pub differences: Vec<String>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            tick: 0,
+        })
+    }
+
+    pub fn record(&mut self, dt: f32, inputs: &[PlayerInput], snapshot: StateSnapshot) {
+        let frame = TraceFrame {
+            tick: self.tick,
+            checksum: snapshot.checksum(),
+            dt,
+            inputs: inputs.to_vec(),
+            snapshot,
+        };
+        self.tick += 1;
+        if let Err(e) = bincode::serialize_into(&mut self.file, &frame).map(|_| self.file.flush()) {
+            eprintln!("failed to write trace: {}", e);
+        }
+    }
+}
+
+impl Replay {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            frames: read_trace(path)?.into(),
+        })
+    }
+}
+
+/// Reads every frame of a trace file
+pub fn read_trace(path: &str) -> std::io::Result<Vec<TraceFrame>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut frames = Vec::new();
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(frame) => frames.push(frame),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                e => return Err(std::io::Error::new(ErrorKind::InvalidData, e.to_string())),
+            },
+        }
+    }
+    Ok(frames)
+}
+
+/// Compares the ticks found in both traces, returns the first one whose checksums differ
+pub fn compare_traces(a: &[TraceFrame], b: &[TraceFrame]) -> Option<Divergence> {
+    let b: HashMap<u32, &TraceFrame> = b.iter().map(|x| (x.tick, x)).collect();
+    for frame in a.iter() {
+        let Some(other) = b.get(&frame.tick) else {
+            continue;
+        };
+        if frame.checksum == other.checksum {
+            continue;
+        }
+        let mut differences = Vec::new();
+        if frame.dt != other.dt {
+            differences.push(format!("dt: {} != {}", frame.dt, other.dt));
+        }
+        if frame.inputs != other.inputs {
+            differences.push("inputs differ".to_string());
+        }
+        differences.extend(diff_snapshots(&frame.snapshot, &other.snapshot));
+        return Some(Divergence { tick: frame.tick, differences });
+    }
+    None
+}
+
+macro_rules! diff_fields {
+    ($out:expr, $prefix:expr, $a:expr, $b:expr, $($field:ident),*) => {
+        $(
+            if $a.$field != $b.$field {
+                $out.push(format!("{}{}: {:?} != {:?}", $prefix, stringify!($field), $a.$field, $b.$field));
+            }
+        )*
+    };
+}
+
+/// Describes every field which differs between the snapshots, actors are matched by handle
+pub fn diff_snapshots(a: &StateSnapshot, b: &StateSnapshot) -> Vec<String> {
+    let mut out = Vec::new();
+    diff_fields!(out, "", a, b, spawner, scores, game_state, round, bounds, rng);
+    let players = |s: &StateSnapshot| -> Vec<ActorHandle> { s.players.iter().map(|x| s.actors[*x].handle).collect() };
+    if players(a) != players(b) {
+        out.push(format!("players: {:?} != {:?}", players(a), players(b)));
+    }
+
+    let b_actors: HashMap<ActorHandle, &ActorSnapshot> = b.actors.iter().map(|x| (x.handle, x)).collect();
+    for actor in a.actors.iter() {
+        let Some(other) = b_actors.get(&actor.handle) else {
+            out.push(format!("actor {:?} ({}) only in first", actor.handle, actor.info));
+            continue;
+        };
+        let prefix = format!("actor {:?} ({}) ", actor.handle, actor.info);
+        diff_fields!(out, prefix, actor, other, info, weapon);
+        diff_actor_state(&mut out, &prefix, &actor.state, &other.state);
+    }
+    for actor in b.actors.iter() {
+        if !a.actors.iter().any(|x| x.handle == actor.handle) {
+            out.push(format!("actor {:?} ({}) only in second", actor.handle, actor.info));
+        }
+    }
+    out
+}
+
+fn diff_actor_state(out: &mut Vec<String>, prefix: &str, a: &ActorState, b: &ActorState) {
+    diff_fields!(
+        out,
+        prefix,
+        a,
+        b,
+        weapon_cooldown,
+        pos,
+        locomotion_dir,
+        vel,
+        attack_dir,
+        owner,
+        health,
+        color,
+        pain_timer,
+        animation,
+        facing,
+        age,
+        effects,
+        hit_actors,
+        pierced,
+        bounced,
+        melee,
+        dead_time
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+
+    use super::*;
+    use crate::{systems, Context, Metadata};
+
+    /// Trace of a zombie walking toward a player
+    fn trace(ticks: u32) -> Vec<TraceFrame> {
+        let mut c = Context { metadata: Metadata::from_disk(&[], false).unwrap(), dt: 0.1, ..Default::default() };
+        for (info, pos) in [("guy", Vec2::ZERO), ("zombie", Vec2::new(5.0, 0.0))] {
+            let info = c.metadata.actors.get(info).unwrap().clone();
+            c.state.spawn_actor(info).pos = pos;
+        }
+        let guy = c.state.actor_handles()[0];
+        c.state.players.push(guy);
+        c.state.scores.push(0);
+        (0..ticks).map(|tick| {
+            systems::simulate(&mut c);
+            let snapshot = StateSnapshot::create_snapshot(&c.state, &c.metadata);
+            TraceFrame { tick, checksum: snapshot.checksum(), dt: c.dt, inputs: Vec::new(), snapshot }
+        }).collect()
+    }
+
+    #[test]
+    fn traces_diverge_at_the_first_tick_which_differs() {
+        let a = trace(10);
+        assert!(compare_traces(&a, &trace(10)).is_none());
+
+        let mut b = a.clone();
+        for frame in b[4..].iter_mut() {
+            let zombie = frame.snapshot.actors.iter_mut().find(|x| x.info == "zombie").unwrap();
+            zombie.state.health -= 1.0;
+            frame.checksum = frame.snapshot.checksum();
+        }
+        let divergence = compare_traces(&a, &b).unwrap();
+
+        assert_eq!(divergence.tick, 4);
+        assert_eq!(divergence.differences.len(), 1, "{:?}", divergence.differences);
+        assert!(divergence.differences[0].contains("(zombie) health"), "{}", divergence.differences[0]);
+    }
+}